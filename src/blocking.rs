@@ -0,0 +1,607 @@
+//! Synchronous downloads, for build scripts and CLI tools that don't otherwise need an async
+//! runtime.
+//!
+//! [`DlFile`] and [`DlFileBuilder`] mirror their async counterparts, with the same [`Delete`]
+//! policy on drop, [`OverwriteBehavior`], [`Durability`], locking, Unix permissions, size
+//! limit, status checks and progress reporting. Downloads read from any [`Read`] source on the
+//! current thread, including a `reqwest::blocking::Response` (with the `reqwest` feature).
+//!
+//! Semaphores, pausing and the page cache are async-only, since they're built around sharing
+//! a runtime between many downloads.
+//!
+//! [`Delete`]: crate::Delete
+//! [`OverwriteBehavior`]: crate::OverwriteBehavior
+//! [`Durability`]: crate::Durability
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+
+use http::StatusCode;
+
+use crate::open::Opener;
+use crate::progress::DlProgress;
+#[cfg(feature = "reqwest")]
+use crate::source::SourceMetadata;
+use crate::status::StatusPolicy;
+use crate::{
+    reader, Delete, DlError, DlErrorKind, DropError, Durability, LockBehavior, OverwriteBehavior,
+    PartialFile,
+};
+
+/// The blocking equivalent of [`crate::DlFileBuilder`].
+pub struct DlFileBuilder<P: AsRef<Path> = PathBuf> {
+    path: P,
+    delete: Delete,
+    durability: Durability,
+    lock: Option<LockBehavior>,
+    status_policy: StatusPolicy,
+    max_bytes: Option<u64>,
+    on_drop_error: Option<fn(&Path, DropError)>,
+    progress: Option<Box<dyn DlProgress>>,
+    #[cfg(unix)]
+    perms: crate::perms::UnixPerms,
+}
+
+impl<P: AsRef<Path>> DlFileBuilder<P> {
+    #[inline]
+    pub fn new(path: P) -> Self {
+        Self {
+            path,
+            delete: Delete::default(),
+            durability: Durability::default(),
+            lock: None,
+            status_policy: StatusPolicy::default(),
+            max_bytes: None,
+            on_drop_error: None,
+            progress: None,
+            #[cfg(unix)]
+            perms: crate::perms::UnixPerms::default(),
+        }
+    }
+
+    #[inline]
+    pub fn delete(mut self, delete: Delete) -> Self {
+        self.delete = delete;
+        self
+    }
+
+    /// See [`crate::DlFileBuilder::durability`].
+    #[inline]
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// See [`crate::DlFileBuilder::accept_statuses`].
+    #[inline]
+    pub fn accept_statuses(mut self, statuses: impl IntoIterator<Item = StatusCode>) -> Self {
+        self.status_policy.accepted = Some(statuses.into_iter().collect());
+        self
+    }
+
+    /// See [`crate::DlFileBuilder::error_body_snippet`].
+    #[inline]
+    pub fn error_body_snippet(mut self, max_bytes: usize) -> Self {
+        self.status_policy.error_body_snippet = max_bytes;
+        self
+    }
+
+    /// See [`crate::DlFileBuilder::max_bytes`]. The limit applies to the whole file, so
+    /// anything before the position a download starts at (when appending to or resuming a
+    /// partial file) counts towards it.
+    #[inline]
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// See [`crate::DlFileBuilder::file_mode`].
+    #[cfg(unix)]
+    #[inline]
+    pub fn file_mode(mut self, mode: u32) -> Self {
+        self.perms.file_mode = Some(mode);
+        self
+    }
+
+    /// See [`crate::DlFileBuilder::dir_mode`].
+    #[cfg(unix)]
+    #[inline]
+    pub fn dir_mode(mut self, mode: u32) -> Self {
+        self.perms.dir_mode = Some(mode);
+        self
+    }
+
+    /// See [`crate::DlFileBuilder::owner`].
+    #[cfg(unix)]
+    #[inline]
+    pub fn owner(mut self, uid: Option<u32>, gid: Option<u32>) -> Self {
+        self.perms.uid = uid;
+        self.perms.gid = gid;
+        self
+    }
+
+    /// Takes a cross-process advisory lock for the download path when opening, which also
+    /// excludes async [`DlFile`]s that lock the same path. See [`LockBehavior`].
+    ///
+    /// [`DlFile`]: crate::DlFile
+    #[inline]
+    pub fn lock(mut self, behavior: LockBehavior) -> Self {
+        self.lock = Some(behavior);
+        self
+    }
+
+    #[inline]
+    pub fn with_progress(mut self, progress: impl DlProgress + 'static) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    #[inline]
+    pub fn on_drop_error(mut self, on_drop_error: fn(&Path, DropError)) -> Self {
+        self.on_drop_error = Some(on_drop_error);
+        self
+    }
+
+    #[cfg(feature = "tracing")]
+    #[inline]
+    pub fn trace_on_drop_error(self, level: tracing::Level) -> Self {
+        self.on_drop_error(crate::builder::tracing_on_drop_error(level))
+    }
+
+    /// See [`crate::DlFileBuilder::open`].
+    pub fn open(self, overwrite_behavior: OverwriteBehavior) -> io::Result<DlFile<P>> {
+        let path = self.path.as_ref();
+        let opener = Opener {
+            durability: self.durability,
+            #[cfg(unix)]
+            perms: self.perms,
+        };
+
+        let created_dirs = opener.create_parent_dirs_blocking(path)?;
+
+        let opened = (|| {
+            // needs to be held before checking/truncating any existing file.
+            let lock = match self.lock {
+                Some(behavior) => Some(crate::lock::acquire_blocking(path, behavior)?),
+                None => None,
+            };
+
+            let file = opener.open_blocking(path, overwrite_behavior)?;
+            io::Result::Ok((lock, file))
+        })();
+
+        opener.finish_dirs_blocking(created_dirs)?;
+        let (lock, file) = opened?;
+
+        Ok(DlFile {
+            path: self.path,
+            delete: self.delete,
+            durability: self.durability,
+            status_policy: self.status_policy,
+            max_bytes: self.max_bytes,
+            progress: self.progress,
+            on_drop_error: self
+                .on_drop_error
+                .unwrap_or(crate::builder::DEFAULT_ON_DROP_ERROR),
+            file: ManuallyDrop::new(file),
+            lock,
+        })
+    }
+
+    #[inline]
+    pub fn open_overwrite(self) -> io::Result<DlFile<P>> {
+        self.open(OverwriteBehavior::Do)
+    }
+
+    #[inline]
+    pub fn open_new(self) -> io::Result<DlFile<P>> {
+        self.open(OverwriteBehavior::Dont)
+    }
+
+    #[inline]
+    pub fn open_overwrite_if_empty(self) -> io::Result<DlFile<P>> {
+        self.open(OverwriteBehavior::DoIfEmpty)
+    }
+}
+
+/// The blocking equivalent of [`crate::DlFile`], which derefs to a [`std::fs::File`].
+pub struct DlFile<P: AsRef<Path> = PathBuf> {
+    path: P,
+    delete: Delete,
+    durability: Durability,
+    status_policy: StatusPolicy,
+    max_bytes: Option<u64>,
+    progress: Option<Box<dyn DlProgress>>,
+    on_drop_error: fn(&Path, DropError),
+    file: ManuallyDrop<File>,
+    // dropped after `file`, and after the `Drop` impl deletes the file (if needed).
+    lock: Option<File>,
+}
+
+impl<P: AsRef<Path>> Deref for DlFile<P> {
+    type Target = File;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.file
+    }
+}
+
+impl<P: AsRef<Path>> DerefMut for DlFile<P> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.file
+    }
+}
+
+impl<P: AsRef<Path>> fmt::Debug for DlFile<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DlFile")
+            .field("path", &self.path.as_ref().display())
+            .field("delete", &self.delete)
+            .field("durability", &self.durability)
+            .field("status_policy", &self.status_policy)
+            .field("max_bytes", &self.max_bytes)
+            .field(
+                "progress",
+                match self.progress.as_ref() {
+                    None => &"None",
+                    Some(_) => &"Some(...)",
+                },
+            )
+            .field("file", &*self.file)
+            .field("locked", &self.lock.is_some())
+            .finish()
+    }
+}
+
+impl<P: AsRef<Path>> Drop for DlFile<P> {
+    fn drop(&mut self) {
+        let path = self.path.as_ref();
+        let should_delete = self.delete.should_delete(path);
+
+        // SAFETY: the file is only taken here, and never used again.
+        drop(unsafe { ManuallyDrop::take(&mut self.file) });
+
+        match should_delete {
+            Ok(true) => {
+                if let Err(error) = std::fs::remove_file(path) {
+                    (self.on_drop_error)(path, DropError::Deleting(error));
+                }
+            }
+            Ok(false) => {}
+            Err(error) => (self.on_drop_error)(path, DropError::Metadata(error)),
+        }
+    }
+}
+
+impl<P: AsRef<Path>> DlFile<P> {
+    #[inline]
+    pub fn builder(path: P) -> DlFileBuilder<P> {
+        DlFileBuilder::new(path)
+    }
+
+    #[inline]
+    pub fn path(&self) -> &Path {
+        self.path.as_ref()
+    }
+
+    /// See [`crate::DlFile::reset`].
+    #[inline]
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.file.rewind()?;
+        self.file.set_len(0)
+    }
+
+    pub fn set_delete(&mut self, delete: Delete) {
+        self.delete = delete;
+    }
+
+    fn partial_file(&self) -> PartialFile {
+        match self.delete {
+            Delete::Yes => PartialFile::Deleted,
+            Delete::No => PartialFile::Kept,
+            Delete::IfEmptyOnDrop => match self.file.metadata() {
+                Ok(meta) if meta.len() == 0 => PartialFile::Deleted,
+                _ => PartialFile::Kept,
+            },
+        }
+    }
+
+    fn download_error(&mut self, kind: impl Into<DlErrorKind>, bytes_written: u64) -> DlError {
+        let kind = kind.into();
+
+        // a download that's too large is never useful, so make sure the default policy
+        // doesn't keep it around.
+        if let (DlErrorKind::TooLarge { .. }, Delete::IfEmptyOnDrop) = (&kind, self.delete) {
+            self.delete = Delete::Yes;
+        }
+
+        DlError::new(
+            kind,
+            self.path.as_ref().to_path_buf(),
+            bytes_written,
+            self.partial_file(),
+        )
+    }
+
+    /// Downloads everything from a [`Read`] source (a child process stdout, decompressor,
+    /// another file, etc), until it hits EOF. `size` is only used for progress and the size
+    /// limit.
+    ///
+    /// The download starts at the current position of the file, and like a resumed async
+    /// download, progress and the size limit include the bytes before it. The returned number
+    /// of bytes (including in errors) only counts what was read from `reader`.
+    pub fn download_from_reader<R>(
+        &mut self,
+        size: Option<u64>,
+        mut reader: R,
+    ) -> Result<u64, DlError>
+    where
+        R: Read,
+    {
+        let offset = match self.file.stream_position() {
+            Ok(offset) => offset,
+            Err(error) => return Err(self.download_error(error, 0)),
+        };
+
+        if let (Some(limit), Some(size)) = (self.max_bytes, size) {
+            if offset + size > limit {
+                let kind = DlErrorKind::TooLarge {
+                    limit,
+                    content_length: Some(offset + size),
+                };
+
+                return Err(self.download_error(kind, 0));
+            }
+        }
+
+        if let Some(ref mut prog) = self.progress {
+            prog.start(self.path.as_ref(), size.map(|size| offset + size));
+
+            if offset > 0 {
+                prog.update(self.path.as_ref(), offset);
+            }
+        }
+
+        let mut bytes_copied = 0;
+
+        match self.copy(&mut reader, offset, &mut bytes_copied) {
+            Ok(()) => Ok(bytes_copied),
+            Err(error) => Err(self.download_error(error, bytes_copied)),
+        }
+    }
+
+    /// Copies `reader` into the file, then flushes (and syncs, depending on the
+    /// [`Durability`]) it and reports the download as finished.
+    fn copy(
+        &mut self,
+        reader: &mut impl Read,
+        offset: u64,
+        bytes_copied: &mut u64,
+    ) -> Result<(), DlErrorKind> {
+        let mut buf = vec![0; reader::DEFAULT_READ_CAPACITY];
+
+        loop {
+            let read = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error.into()),
+            };
+
+            // check before writing any of the chunk, so nothing past the limit is written
+            if let Some(limit) = self.max_bytes {
+                if offset + *bytes_copied + read as u64 > limit {
+                    return Err(DlErrorKind::TooLarge {
+                        limit,
+                        content_length: None,
+                    });
+                }
+            }
+
+            self.file.write_all(&buf[..read])?;
+            *bytes_copied += read as u64;
+
+            if let Some(ref mut prog) = self.progress {
+                prog.update(self.path.as_ref(), offset + *bytes_copied);
+            }
+        }
+
+        self.file.flush()?;
+
+        if self.durability == Durability::Sync {
+            crate::sync::sync_file_and_parent_blocking(&self.file, self.path.as_ref())?;
+        }
+
+        if let Some(ref mut prog) = self.progress {
+            prog.finished(self.path.as_ref());
+        }
+
+        Ok(())
+    }
+
+    /// Downloads the body of a response, checking its status first. See
+    /// [`crate::DlFile::download_from_source`].
+    #[cfg(feature = "reqwest")]
+    pub fn download_from_response(
+        &mut self,
+        mut response: reqwest::blocking::Response,
+    ) -> Result<u64, DlError> {
+        let metadata = SourceMetadata::from_response_parts(
+            response.status(),
+            response.headers(),
+            response.content_length(),
+        )
+        .with_url(response.url().as_str());
+
+        if let Err(mut kind) = self.check_status(&metadata) {
+            if let DlErrorKind::Status { ref mut body, .. } = kind {
+                let max = self.status_policy.error_body_snippet;
+                *body = read_body_snippet(&mut response, max);
+            }
+
+            return Err(self.download_error(kind, 0));
+        }
+
+        self.download_from_reader(metadata.content_length, response)
+    }
+
+    /// Checks the status of a source against the status policy, before anything is written.
+    /// A rejected status is returned without a body snippet.
+    #[cfg(feature = "reqwest")]
+    fn check_status(&mut self, metadata: &SourceMetadata) -> Result<(), DlErrorKind> {
+        if metadata.status.is_none() {
+            return Ok(());
+        }
+
+        let position = self.file.stream_position()?;
+        self.status_policy.check(metadata, position)
+    }
+}
+
+/// The blocking equivalent of [`status::read_body_snippet`].
+///
+/// [`status::read_body_snippet`]: crate::status::read_body_snippet
+#[cfg(feature = "reqwest")]
+fn read_body_snippet(reader: impl Read, max: usize) -> Option<bytes::Bytes> {
+    if max == 0 {
+        return None;
+    }
+
+    let mut snippet = Vec::new();
+    // errors just end the snippet early, keeping whatever was read.
+    let _ = reader.take(max as u64).read_to_end(&mut snippet);
+
+    Some(snippet.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use super::DlFile;
+    use crate::{Delete, DlErrorKind, LockBehavior, PartialFile};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("dl-file-blocking-{name}-{}", std::process::id()))
+    }
+
+    #[test]
+    fn deletes_empty_files_on_drop() {
+        let (empty, written) = (temp_path("empty"), temp_path("written"));
+
+        let file = DlFile::builder(empty.clone()).open_overwrite().unwrap();
+        drop(file);
+
+        let mut file = DlFile::builder(written.clone()).open_overwrite().unwrap();
+        let copied = file.download_from_reader(Some(5), &b"hello"[..]).unwrap();
+        drop(file);
+
+        let contents = std::fs::read(&written).unwrap();
+        std::fs::remove_file(&written).unwrap();
+
+        assert!(!empty.exists());
+        assert_eq!(copied, 5);
+        assert_eq!(contents, b"hello");
+    }
+
+    #[test]
+    fn deletes_downloads_over_the_limit() {
+        let path = temp_path("too-large");
+        let mut file = DlFile::builder(path.clone())
+            .max_bytes(4)
+            .open_overwrite()
+            .unwrap();
+
+        let error = file.download_from_reader(None, &b"hello"[..]).unwrap_err();
+        assert!(matches!(
+            error.kind(),
+            DlErrorKind::TooLarge {
+                limit: 4,
+                content_length: None,
+            }
+        ));
+        assert_eq!(error.bytes_written(), 0);
+        assert_eq!(error.partial_file(), PartialFile::Deleted);
+
+        // the file exists until it's dropped, even if nothing was written.
+        assert!(path.exists());
+        drop(file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn counts_appended_downloads_against_the_limit() {
+        let path = temp_path("appended");
+        let mut file = DlFile::builder(path.clone())
+            .max_bytes(8)
+            .delete(Delete::No)
+            .open_overwrite()
+            .unwrap();
+
+        file.write_all(b"prefix").unwrap();
+
+        let error = file
+            .download_from_reader(Some(5), &b"hello"[..])
+            .unwrap_err();
+        assert!(matches!(
+            error.kind(),
+            DlErrorKind::TooLarge {
+                limit: 8,
+                content_length: Some(11),
+            }
+        ));
+        assert_eq!(error.partial_file(), PartialFile::Kept);
+
+        // without a known size, the download is stopped before it goes past the limit.
+        let error = file.download_from_reader(None, &b"hello"[..]).unwrap_err();
+        assert!(matches!(
+            error.kind(),
+            DlErrorKind::TooLarge {
+                limit: 8,
+                content_length: None,
+            }
+        ));
+
+        let copied = file.download_from_reader(None, &b"ok"[..]).unwrap();
+        drop(file);
+
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(copied, 2);
+        assert_eq!(contents, b"prefixok");
+    }
+
+    #[tokio::test]
+    async fn locks_exclude_async_downloads() {
+        let path = temp_path("locked");
+
+        let file = crate::DlFile::builder(path.clone())
+            .lock(LockBehavior::FailFast)
+            .open_overwrite()
+            .await
+            .unwrap();
+
+        let error = DlFile::builder(path.clone())
+            .lock(LockBehavior::FailFast)
+            .open_overwrite()
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::WouldBlock);
+
+        drop(file);
+
+        let file = DlFile::builder(path.clone())
+            .lock(LockBehavior::FailFast)
+            .open_overwrite()
+            .unwrap();
+        drop(file);
+
+        let _ = std::fs::remove_file(crate::lock::lock_path(&path));
+    }
+}
@@ -3,17 +3,38 @@ use std::mem::ManuallyDrop;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+use http::StatusCode;
 use tokio::sync::Semaphore;
 
+use crate::open::Opener;
 use crate::progress::DlProgress;
-use crate::{Delete, DlFile, DlFileWriter, DropError, OverwriteBehavior};
+use crate::source::SourceMetadata;
+use crate::status::StatusPolicy;
+use crate::{
+    Delete, DlFile, DlFileWriter, DropError, Durability, LockBehavior, OverwriteBehavior,
+    PageCache, PauseHandle, PermitWeight,
+};
 
 pub struct DlFileBuilder<P: AsRef<Path> = PathBuf> {
     path: P,
     semaphore: Option<Arc<Semaphore>>,
+    permit_weight: PermitWeight,
+    pause: Option<PauseHandle>,
     delete: Delete,
+    durability: Durability,
+    page_cache: PageCache,
+    lock: Option<LockBehavior>,
+    status_policy: StatusPolicy,
+    max_bytes: Option<u64>,
+    write_buffer: usize,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    io_uring: bool,
+    #[cfg(feature = "reqwest")]
+    retry_policy: crate::RetryPolicy,
     on_drop_error: Option<fn(&Path, DropError)>,
     progress: Option<Box<dyn DlProgress>>,
+    #[cfg(unix)]
+    perms: crate::perms::UnixPerms,
 }
 
 impl<P: AsRef<Path>> DlFileBuilder<P> {
@@ -22,9 +43,23 @@ impl<P: AsRef<Path>> DlFileBuilder<P> {
         Self {
             path,
             semaphore: None,
+            permit_weight: PermitWeight::default(),
+            pause: None,
             on_drop_error: None,
             delete: Delete::default(),
+            durability: Durability::default(),
+            page_cache: PageCache::default(),
+            lock: None,
+            status_policy: StatusPolicy::default(),
+            max_bytes: None,
+            write_buffer: crate::driver::DEFAULT_WRITE_BUFFER,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            io_uring: false,
+            #[cfg(feature = "reqwest")]
+            retry_policy: crate::RetryPolicy::NONE,
             progress: None,
+            #[cfg(unix)]
+            perms: crate::perms::UnixPerms::default(),
         }
     }
 
@@ -34,6 +69,145 @@ impl<P: AsRef<Path>> DlFileBuilder<P> {
         self
     }
 
+    /// Sets how durable the file needs to be before a download is reported as finished.
+    ///
+    /// With [`Durability::Sync`], any parent directories created by [`open`] are also
+    /// fsynced as part of opening the file.
+    ///
+    /// [`open`]: Self::open
+    #[inline]
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Sets what happens to the OS page cache as downloads are written. Defaults to
+    /// [`PageCache::Keep`].
+    ///
+    /// [`PageCache::Drop`] keeps multi-hundred-GB downloads from evicting the cache of
+    /// everything else on the machine.
+    #[inline]
+    pub fn page_cache(mut self, page_cache: PageCache) -> Self {
+        self.page_cache = page_cache;
+        self
+    }
+
+    /// Sets the exact response statuses that are accepted when downloading from an HTTP
+    /// source, replacing the default of any `2xx` status.
+    ///
+    /// `206 Partial Content` is only ever accepted when the partial response starts where the
+    /// file is being written.
+    #[inline]
+    pub fn accept_statuses(mut self, statuses: impl IntoIterator<Item = StatusCode>) -> Self {
+        self.status_policy.accepted = Some(statuses.into_iter().collect());
+        self
+    }
+
+    /// Keeps up to `max_bytes` of the body of a rejected response in the returned error, to
+    /// help diagnose why a download was rejected. Defaults to 0 (no snippet).
+    #[inline]
+    pub fn error_body_snippet(mut self, max_bytes: usize) -> Self {
+        self.status_policy.error_body_snippet = max_bytes;
+        self
+    }
+
+    /// Limits the size of downloads into the file, to protect the disk from runaway
+    /// responses.
+    ///
+    /// Downloads with a known size over the limit are rejected before anything is written,
+    /// and streams that grow past the limit are aborted before the limit is exceeded. Both
+    /// return a [`DlErrorKind::TooLarge`] error, and with the default
+    /// [`Delete::IfEmptyOnDrop`] policy, the partial file is deleted on drop.
+    ///
+    /// [`DlErrorKind::TooLarge`]: crate::DlErrorKind::TooLarge
+    #[inline]
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Sets how many bytes are coalesced from a stream before they're written to the file,
+    /// to cut down on the number of writes when the source produces lots of small chunks.
+    /// Buffered chunks are written with a single vectored write.
+    ///
+    /// Defaults to 256 KiB. `0` writes every chunk as soon as it's received.
+    #[inline]
+    pub fn write_buffer(mut self, capacity: usize) -> Self {
+        self.write_buffer = capacity;
+        self
+    }
+
+    /// Writes downloads through io_uring instead of tokio's blocking thread pool. Defaults to
+    /// `false`.
+    ///
+    /// Falls back to the regular writer if io_uring isn't supported (or allowed) on the
+    /// system. Writes are submitted in chunks of [`write_buffer`] bytes.
+    ///
+    /// [`write_buffer`]: Self::write_buffer
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    #[inline]
+    pub fn io_uring(mut self, enabled: bool) -> Self {
+        self.io_uring = enabled;
+        self
+    }
+
+    /// Sets how downloads that can rebuild their request (i.e. [`DlFile::download_with`])
+    /// are retried. Defaults to [`RetryPolicy::NONE`].
+    ///
+    /// [`RetryPolicy::NONE`]: crate::RetryPolicy::NONE
+    #[cfg(feature = "reqwest")]
+    #[inline]
+    pub fn retry_policy(mut self, retry_policy: crate::RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Takes a cross-process advisory lock for the download path when opening, so multiple
+    /// processes downloading the same file don't clobber each other.
+    ///
+    /// See [`LockBehavior`] for details on how the lock is taken.
+    #[inline]
+    pub fn lock(mut self, behavior: LockBehavior) -> Self {
+        self.lock = Some(behavior);
+        self
+    }
+
+    /// Sets the exact mode (i.e. `0o755` for executables, `0o600` for secrets) of the
+    /// downloaded file.
+    ///
+    /// The mode is applied when the file is created, and isn't affected by the process umask.
+    /// Existing files that are opened (and overwritten) also have their mode set.
+    #[cfg(unix)]
+    #[inline]
+    pub fn file_mode(mut self, mode: u32) -> Self {
+        self.perms.file_mode = Some(mode);
+        self
+    }
+
+    /// Sets the exact mode of any parent directories created when opening the file.
+    /// Directories that already exist (including any created by another process while
+    /// opening) are left untouched.
+    ///
+    /// The directories are created with the owner bits set, and only get the exact mode once
+    /// the file is open, so a mode without search permission (like `0o600`) doesn't stop the
+    /// file from being created.
+    #[cfg(unix)]
+    #[inline]
+    pub fn dir_mode(mut self, mode: u32) -> Self {
+        self.perms.dir_mode = Some(mode);
+        self
+    }
+
+    /// Sets the owning user and/or group of the file, and of any parent directories
+    /// created when opening it. `None` leaves that id unchanged.
+    #[cfg(unix)]
+    #[inline]
+    pub fn owner(mut self, uid: Option<u32>, gid: Option<u32>) -> Self {
+        self.perms.uid = uid;
+        self.perms.gid = gid;
+        self
+    }
+
     #[inline]
     pub fn with_semaphore(mut self, semaphore: Arc<Semaphore>) -> Self {
         self.semaphore = Some(semaphore);
@@ -45,6 +219,21 @@ impl<P: AsRef<Path>> DlFileBuilder<P> {
         self.with_semaphore(Arc::clone(semaphore))
     }
 
+    /// Sets how many permits downloads take from the semaphore. Defaults to
+    /// [`PermitWeight::One`].
+    #[inline]
+    pub fn permit_weight(mut self, permit_weight: PermitWeight) -> Self {
+        self.permit_weight = permit_weight;
+        self
+    }
+
+    /// Lets downloads into the file be paused and resumed with `pause`.
+    #[inline]
+    pub fn pause_handle(mut self, pause: PauseHandle) -> Self {
+        self.pause = Some(pause);
+        self
+    }
+
     #[inline]
     pub fn with_progress(mut self, progress: impl DlProgress + 'static) -> Self {
         self.progress = Some(Box::new(progress));
@@ -60,13 +249,7 @@ impl<P: AsRef<Path>> DlFileBuilder<P> {
     #[cfg(feature = "tracing")]
     #[inline]
     pub fn trace_on_drop_error(self, level: tracing::Level) -> Self {
-        self.on_drop_error(match level {
-            tracing::Level::TRACE => default_trace_on_drop_error,
-            tracing::Level::DEBUG => default_debug_on_drop_error,
-            tracing::Level::INFO => default_info_on_drop_error,
-            tracing::Level::WARN => default_warn_on_drop_error,
-            tracing::Level::ERROR => default_error_on_drop_error,
-        })
+        self.on_drop_error(tracing_on_drop_error(level))
     }
 
     pub async fn open_as_writer(
@@ -81,54 +264,106 @@ impl<P: AsRef<Path>> DlFileBuilder<P> {
 
     pub async fn open(self, overwrite_behavior: OverwriteBehavior) -> io::Result<DlFile<P>> {
         let path = self.path.as_ref();
+        let opener = Opener {
+            durability: self.durability,
+            #[cfg(unix)]
+            perms: self.perms,
+        };
 
-        if let Some(parent) = path.parent() {
-            if !tokio::fs::try_exists(parent).await? {
-                tokio::fs::create_dir_all(parent).await?;
-            }
+        let created_dirs = opener.create_parent_dirs(path).await?;
+
+        let opened = async {
+            // needs to be held before checking/truncating any existing file.
+            let lock = match self.lock {
+                Some(behavior) => Some(crate::lock::acquire(path, behavior).await?),
+                None => None,
+            };
+
+            let file = opener.open(path, overwrite_behavior).await?;
+            io::Result::Ok((lock, file))
         }
+        .await;
 
-        let file = match overwrite_behavior {
-            OverwriteBehavior::Do => tokio::fs::File::create(path).await?,
-            OverwriteBehavior::Dont => {
-                tokio::fs::OpenOptions::new()
-                    .write(true)
-                    .create_new(true)
-                    .open(path)
-                    .await?
-            }
-            OverwriteBehavior::DoIfEmpty => match tokio::fs::metadata(path).await {
-                Ok(meta) if meta.len() == 0 => tokio::fs::File::create(path).await?,
-                Ok(meta) => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::AlreadyExists,
-                        format!(
-                            "non-empty ({} bytes) file '{}' already exists",
-                            meta.len(),
-                            self.path.as_ref().display()
-                        ),
-                    ));
-                }
-                Err(error) if error.kind() == io::ErrorKind::NotFound => {
-                    tokio::fs::File::create(path).await?
-                }
-                Err(error) => return Err(error),
-            },
-        };
+        opener.finish_dirs(created_dirs).await?;
+        let (lock, file) = opened?;
 
         Ok(DlFile {
             path: self.path,
             semaphore: self.semaphore,
-            #[cfg(not(feature = "tracing"))]
-            on_drop_error: self.on_drop_error.unwrap_or(default_on_drop_error),
-            #[cfg(feature = "tracing")]
-            on_drop_error: self.on_drop_error.unwrap_or(default_error_on_drop_error),
+            permit_weight: self.permit_weight,
+            pause: self.pause,
+            on_drop_error: self.on_drop_error.unwrap_or(DEFAULT_ON_DROP_ERROR),
             delete: self.delete,
+            durability: self.durability,
+            page_cache: self.page_cache,
+            status_policy: self.status_policy,
+            max_bytes: self.max_bytes,
+            write_buffer: self.write_buffer,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            io_uring: self.io_uring,
+            #[cfg(feature = "reqwest")]
+            retry_policy: self.retry_policy,
             progress: self.progress,
             file: ManuallyDrop::new(file),
+            lock,
         })
     }
 
+    /// Opens a file inside the directory this builder was created with, named after the
+    /// download: from the `Content-Disposition` header of the response (preferring the
+    /// RFC 5987 `filename*` parameter), or else the last segment of the URL path. See
+    /// [`infer_filename`] for how the name is picked and sanitized.
+    ///
+    /// The chosen path is available from [`DlFile::path`]. Returns an
+    /// [`io::ErrorKind::InvalidInput`] error if no usable name can be inferred.
+    ///
+    /// [`infer_filename`]: crate::filename::infer_filename
+    pub async fn open_in_dir(
+        self,
+        overwrite_behavior: OverwriteBehavior,
+        metadata: &SourceMetadata,
+    ) -> io::Result<DlFile<PathBuf>> {
+        let file_name = match crate::filename::infer_filename(metadata) {
+            Some(file_name) => file_name,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "couldn't infer a file name to download into '{}'",
+                        self.path.as_ref().display()
+                    ),
+                ))
+            }
+        };
+
+        let path = self.path.as_ref().join(file_name);
+        self.with_path(path).open(overwrite_behavior).await
+    }
+
+    fn with_path<Q: AsRef<Path>>(self, path: Q) -> DlFileBuilder<Q> {
+        DlFileBuilder {
+            path,
+            semaphore: self.semaphore,
+            permit_weight: self.permit_weight,
+            pause: self.pause,
+            delete: self.delete,
+            durability: self.durability,
+            page_cache: self.page_cache,
+            lock: self.lock,
+            status_policy: self.status_policy,
+            max_bytes: self.max_bytes,
+            write_buffer: self.write_buffer,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            io_uring: self.io_uring,
+            #[cfg(feature = "reqwest")]
+            retry_policy: self.retry_policy,
+            on_drop_error: self.on_drop_error,
+            progress: self.progress,
+            #[cfg(unix)]
+            perms: self.perms,
+        }
+    }
+
     #[inline]
     pub async fn open_overwrite(self) -> io::Result<DlFile<P>> {
         self.open(OverwriteBehavior::Do).await
@@ -145,6 +380,24 @@ impl<P: AsRef<Path>> DlFileBuilder<P> {
     }
 }
 
+/// How errors deleting a file on drop are reported, unless an `on_drop_error` is set.
+#[cfg(not(feature = "tracing"))]
+pub(crate) const DEFAULT_ON_DROP_ERROR: fn(&Path, DropError) = default_on_drop_error;
+#[cfg(feature = "tracing")]
+pub(crate) const DEFAULT_ON_DROP_ERROR: fn(&Path, DropError) = default_error_on_drop_error;
+
+/// The `on_drop_error` that logs drop errors as tracing events at `level`.
+#[cfg(feature = "tracing")]
+pub(crate) fn tracing_on_drop_error(level: tracing::Level) -> fn(&Path, DropError) {
+    match level {
+        tracing::Level::TRACE => default_trace_on_drop_error,
+        tracing::Level::DEBUG => default_debug_on_drop_error,
+        tracing::Level::INFO => default_info_on_drop_error,
+        tracing::Level::WARN => default_warn_on_drop_error,
+        tracing::Level::ERROR => default_error_on_drop_error,
+    }
+}
+
 #[cfg(not(feature = "tracing"))]
 #[inline]
 fn default_on_drop_error(path: &Path, error: DropError) {
@@ -0,0 +1,314 @@
+//! Keeping downloads out of the OS page cache, see [`PageCache`].
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use tokio::fs::File;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::task::JoinHandle;
+
+use crate::PageCache;
+
+/// How much of a download is written back (and then dropped from the page cache) at a time.
+/// Window boundaries are multiples of this in the file, so they're always page aligned.
+const WINDOW: u64 = 8 * 1024 * 1024;
+
+/// Writes back a download in windows as it's written, dropping each window from the page cache
+/// once it's on disk.
+///
+/// Writeback for a window is started as soon as it's complete, and the previous window is
+/// waited on (and dropped) at the same time. So writes never get more than a couple of windows
+/// ahead of the disk, which is what keeps the dirty pages (and the cache) small.
+pub(crate) struct CacheDropper {
+    file: Arc<std::fs::File>,
+    // where the download starts in the file, which positions are relative to.
+    start: u64,
+    // everything before this has been dropped from the page cache.
+    dropped: u64,
+    // writeback has been started for everything before this.
+    started: u64,
+    task: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl CacheDropper {
+    /// Returns `None` if the page cache is kept, or can't be dropped on this platform.
+    ///
+    /// The download starts at the current position of `file`, which is flushed first so the
+    /// position is where the next write lands.
+    pub(crate) async fn new(file: &mut File, page_cache: PageCache) -> io::Result<Option<Self>> {
+        if page_cache == PageCache::Keep || cfg!(not(target_os = "linux")) {
+            return Ok(None);
+        }
+
+        file.flush().await?;
+        let start = file.stream_position().await?;
+
+        Self::at(file, start, page_cache)
+    }
+
+    /// Like [`new`], for a download that starts at `start` in the file.
+    ///
+    /// Windows are only written back as the download is written sequentially from `start`, so
+    /// anything written past a gap stays cached until the download is finished.
+    ///
+    /// [`new`]: Self::new
+    pub(crate) fn at(file: &File, start: u64, page_cache: PageCache) -> io::Result<Option<Self>> {
+        if page_cache == PageCache::Keep || cfg!(not(target_os = "linux")) {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            file: Arc::new(crate::sync::clone_std(file)?),
+            start,
+            dropped: start,
+            started: start,
+            task: None,
+        }))
+    }
+
+    /// Where the download starts in the file.
+    #[inline]
+    pub(crate) fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Called with the number of bytes of the download that have been handed to the OS so far,
+    /// before writing any more. Starts writeback of any newly completed window, and drops the
+    /// one before it.
+    ///
+    /// Returns `Pending` while the previous window is still being written back.
+    pub(crate) fn poll_written(
+        &mut self,
+        cx: &mut Context<'_>,
+        written: u64,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.poll_task(cx))?;
+
+        // tokio keeps (at most) one write in flight, which is much smaller than a window, so
+        // everything before the last complete window has landed by the time it's dropped.
+        let end = (self.start + written) / WINDOW * WINDOW;
+
+        if end > self.started {
+            let (from, to) = (self.dropped, self.started);
+            self.spawn(move |file| {
+                sys::write_back_and_drop(file, from, to)?;
+                sys::start_write_back(file, to, end)
+            });
+
+            self.dropped = self.started;
+            self.started = end;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Writes back and drops everything that's left, once the download is flushed.
+    pub(crate) fn poll_finish(
+        &mut self,
+        cx: &mut Context<'_>,
+        written: u64,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.poll_task(cx))?;
+
+        let end = self.start + written;
+
+        if end > self.dropped {
+            let from = self.dropped;
+            self.spawn(move |file| sys::write_back_and_drop(file, from, end));
+
+            self.dropped = end;
+            self.started = self.started.max(end);
+        }
+
+        self.poll_task(cx)
+    }
+
+    #[cfg(target_os = "linux")]
+    #[inline]
+    pub(crate) async fn written(&mut self, written: u64) -> io::Result<()> {
+        futures::future::poll_fn(|cx| self.poll_written(cx, written)).await
+    }
+
+    #[cfg(target_os = "linux")]
+    #[inline]
+    pub(crate) async fn finish(&mut self, written: u64) -> io::Result<()> {
+        futures::future::poll_fn(|cx| self.poll_finish(cx, written)).await
+    }
+
+    fn spawn(&mut self, f: impl FnOnce(&std::fs::File) -> io::Result<()> + Send + 'static) {
+        let file = Arc::clone(&self.file);
+        self.task = Some(tokio::task::spawn_blocking(move || f(&file)));
+    }
+
+    fn poll_task(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Some(ref mut task) = self.task else {
+            return Poll::Ready(Ok(()));
+        };
+
+        let result = ready!(Pin::new(task).poll(cx));
+        self.task = None;
+
+        Poll::Ready(result.map_err(io::Error::other)?)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::io;
+    use std::os::fd::AsRawFd;
+
+    /// Starts writeback of `from..to`, without waiting for it.
+    pub(super) fn start_write_back(file: &std::fs::File, from: u64, to: u64) -> io::Result<()> {
+        sync_file_range(file, from, to, libc::SYNC_FILE_RANGE_WRITE)
+    }
+
+    /// Writes back `from..to` (waiting for any writeback that's already started), then drops
+    /// it from the page cache.
+    pub(super) fn write_back_and_drop(file: &std::fs::File, from: u64, to: u64) -> io::Result<()> {
+        if to <= from {
+            return Ok(());
+        }
+
+        let flags = libc::SYNC_FILE_RANGE_WAIT_BEFORE
+            | libc::SYNC_FILE_RANGE_WRITE
+            | libc::SYNC_FILE_RANGE_WAIT_AFTER;
+
+        sync_file_range(file, from, to, flags)?;
+
+        // SAFETY: the file descriptor is valid as long as `file` is borrowed.
+        let result = unsafe {
+            libc::posix_fadvise(
+                file.as_raw_fd(),
+                from as libc::off_t,
+                (to - from) as libc::off_t,
+                libc::POSIX_FADV_DONTNEED,
+            )
+        };
+
+        // posix_fadvise returns the error, instead of setting errno.
+        match result {
+            0 => Ok(()),
+            error => ignore_unsupported(io::Error::from_raw_os_error(error)),
+        }
+    }
+
+    fn sync_file_range(file: &std::fs::File, from: u64, to: u64, flags: u32) -> io::Result<()> {
+        // SAFETY: the file descriptor is valid as long as `file` is borrowed.
+        let result = unsafe {
+            libc::sync_file_range(
+                file.as_raw_fd(),
+                from as libc::off64_t,
+                (to - from) as libc::off64_t,
+                flags,
+            )
+        };
+
+        match result {
+            -1 => ignore_unsupported(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Dropping the cache is only advice, so filesystems that don't support it (i.e. some
+    /// network filesystems) aren't worth failing the download over. Actual I/O errors are.
+    fn ignore_unsupported(error: io::Error) -> io::Result<()> {
+        match error.raw_os_error() {
+            Some(libc::EINVAL | libc::ESPIPE | libc::ENOSYS | libc::EOPNOTSUPP) => Ok(()),
+            _ => Err(error),
+        }
+    }
+}
+
+/// Never called, since [`CacheDropper::new`] returns `None` on other platforms.
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::io;
+
+    pub(super) fn start_write_back(_file: &std::fs::File, _from: u64, _to: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub(super) fn write_back_and_drop(
+        _file: &std::fs::File,
+        _from: u64,
+        _to: u64,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use bytes::Bytes;
+
+    use super::{CacheDropper, WINDOW};
+    use crate::{Delete, DlFile, PageCache};
+
+    #[tokio::test]
+    async fn drops_complete_windows_behind_the_download() {
+        let path = std::env::temp_dir().join(format!("dl-file-cache-{}", std::process::id()));
+        let file = tokio::fs::File::create(&path).await.unwrap();
+        file.set_len(3 * WINDOW).await.unwrap();
+
+        assert!(CacheDropper::at(&file, 100, PageCache::Keep)
+            .unwrap()
+            .is_none());
+
+        let mut cache = CacheDropper::at(&file, 100, PageCache::Drop)
+            .unwrap()
+            .unwrap();
+
+        // nothing happens until a window is complete
+        cache.written(WINDOW - 200).await.unwrap();
+        assert_eq!((cache.dropped, cache.started), (100, 100));
+
+        cache.written(WINDOW).await.unwrap();
+        assert_eq!((cache.dropped, cache.started), (100, WINDOW));
+
+        // the window before the latest one is dropped
+        cache.written(2 * WINDOW + 50).await.unwrap();
+        assert_eq!((cache.dropped, cache.started), (WINDOW, 2 * WINDOW));
+
+        cache.finish(2 * WINDOW + 50).await.unwrap();
+        let end = 2 * WINDOW + 150;
+        assert_eq!((cache.dropped, cache.started), (end, end));
+
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn downloads_dropping_the_cache_are_intact() {
+        let path = std::env::temp_dir().join(format!("dl-file-cache-dl-{}", std::process::id()));
+        let mut file = DlFile::builder(&path)
+            .page_cache(PageCache::Drop)
+            .delete(Delete::No)
+            .open_overwrite()
+            .await
+            .unwrap();
+
+        let len = 2 * WINDOW + 12345;
+        let chunks = (0..len / 4096 + 1).map(|i| {
+            let size = (len - i * 4096).min(4096) as usize;
+            Ok(Bytes::from(vec![i as u8; size]))
+        });
+
+        let copied = file
+            .download_from_io_stream(Some(len), futures::stream::iter(chunks))
+            .await
+            .unwrap();
+
+        let contents = std::fs::read(&path).unwrap();
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(copied, len);
+        assert_eq!(contents.len() as u64, len);
+        assert!(contents
+            .chunks(4096)
+            .enumerate()
+            .all(|(i, chunk)| chunk.iter().all(|byte| *byte == i as u8)));
+    }
+}
@@ -0,0 +1,443 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[cfg(target_os = "linux")]
+use crate::cache::CacheDropper;
+use crate::{DlError, DlErrorKind, DlFile};
+
+impl<P: AsRef<Path>> DlFile<P> {
+    /// Copies a local file into this [`DlFile`].
+    ///
+    /// On Linux, this first tries to reflink the file (`FICLONE`, if this file is empty and
+    /// both files are on a filesystem that supports it), then `copy_file_range`, which lets
+    /// the kernel (or NFS server) do the copy. If neither are supported (or the source isn't a
+    /// regular file, i.e. a pipe), this falls back to a buffered copy. The semaphore, pause
+    /// handle, page cache policy and progress are used in every case.
+    ///
+    /// Regular files are copied up to their length when opened, so a file that's still being
+    /// written to can't grow the copy past the size limit. The buffered copy of anything else
+    /// reads until EOF, enforcing the limit as it goes.
+    pub async fn copy_from_path(&mut self, src: impl AsRef<Path>) -> Result<u64, DlError> {
+        let (src, len, is_file) = match open_source(src.as_ref()).await {
+            Ok(opened) => opened,
+            Err(error) => return Err(self.download_error(error, 0).await),
+        };
+
+        if let Some(limit) = self.max_bytes.filter(|limit| len > *limit) {
+            let kind = DlErrorKind::TooLarge {
+                limit,
+                content_length: Some(len),
+            };
+
+            return Err(self.download_error(kind, 0).await);
+        }
+
+        #[cfg(target_os = "linux")]
+        let src = if !is_file {
+            src
+        } else {
+            let src = src.into_std().await;
+            let mut copied = 0;
+
+            match self.copy_accelerated(&src, len, &mut copied).await {
+                Ok(Some(copied)) => return Ok(copied),
+                Ok(None) => (),
+                Err(error) => return Err(self.download_error(error, copied).await),
+            }
+
+            tokio::fs::File::from_std(src)
+        };
+
+        match is_file {
+            true => {
+                let src = tokio::io::AsyncReadExt::take(src, len);
+                self.download_from_reader(Some(len), src).await
+            }
+            false => self.download_from_reader(None, src).await,
+        }
+    }
+
+    /// Copies the file at a `file://` URL into this [`DlFile`]. See [`copy_from_path`] for
+    /// details.
+    ///
+    /// [`copy_from_path`]: Self::copy_from_path
+    #[inline]
+    pub async fn copy_from_file_url(&mut self, url: &str) -> Result<u64, DlError> {
+        match file_url_to_path(url) {
+            Ok(path) => self.copy_from_path(path).await,
+            Err(error) => Err(self.download_error(error, 0).await),
+        }
+    }
+
+    /// Returns `Ok(None)` if neither `FICLONE` or `copy_file_range` are supported between
+    /// the 2 files, without having started the progress. `copied` tracks the progress, so it's
+    /// still available if an error is returned.
+    ///
+    /// Copies at most `len` bytes. Reflinks share the extents of the whole source file, which
+    /// is truncated back to `len` if it grew since it was opened.
+    #[cfg(target_os = "linux")]
+    async fn copy_accelerated(
+        &mut self,
+        src: &std::fs::File,
+        len: u64,
+        copied: &mut u64,
+    ) -> io::Result<Option<u64>> {
+        use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+        /// The max number of bytes copied by a single `copy_file_range` call, which is also
+        /// how often progress is updated.
+        const COPY_CHUNK: usize = 8 * 1024 * 1024;
+
+        let mut permit = self.acquire_permit(Some(len)).await?;
+        let mut paused = false;
+
+        self.file.flush().await?;
+        let start = self.file.stream_position().await?;
+        let dst = crate::sync::clone_std(&self.file)?;
+        let mut cache = CacheDropper::at(&self.file, start, self.page_cache)?;
+
+        let mut cloned = false;
+
+        if start == 0 && self.file.metadata().await?.len() == 0 {
+            self.wait_while_paused(&mut paused, &mut permit).await?;
+            let (src, dst) = (src.try_clone()?, dst.try_clone()?);
+
+            cloned = match run_blocking(move || linux::ficlone(&src, &dst)).await {
+                Ok(()) => true,
+                Err(error) if linux::is_clone_unsupported(&error) => false,
+                Err(error) => return Err(error),
+            };
+
+            if cloned {
+                let cloned_len = self.file.metadata().await?.len();
+
+                if cloned_len > len {
+                    self.file.set_len(len).await?;
+                }
+
+                *copied = cloned_len.min(len);
+
+                if let Some(ref mut prog) = self.progress {
+                    prog.start(self.path.as_ref(), Some(len));
+                    prog.update(self.path.as_ref(), *copied);
+                }
+            }
+        }
+
+        if !cloned {
+            loop {
+                self.wait_while_paused(&mut paused, &mut permit).await?;
+
+                let (src, dst) = (src.try_clone()?, dst.try_clone()?);
+                let offset = *copied;
+                let chunk = (len - offset).min(COPY_CHUNK as u64) as usize;
+
+                let result = match chunk {
+                    0 => Ok(0),
+                    chunk => {
+                        run_blocking(move || {
+                            linux::copy_file_range(&src, offset, &dst, start + offset, chunk)
+                        })
+                        .await
+                    }
+                };
+
+                let count = match result {
+                    Ok(count) => count,
+                    Err(error) if *copied == 0 && linux::is_unsupported(&error) => return Ok(None),
+                    Err(error) => return Err(error),
+                };
+
+                // started even if the source is empty, since it's finished below either way.
+                if *copied == 0 {
+                    if let Some(ref mut prog) = self.progress {
+                        prog.start(self.path.as_ref(), Some(len));
+                    }
+                }
+
+                if count == 0 {
+                    break;
+                }
+
+                *copied += count as u64;
+
+                if let Some(ref mut prog) = self.progress {
+                    prog.update(self.path.as_ref(), *copied);
+                }
+
+                if let Some(ref mut cache) = cache {
+                    cache.written(*copied).await?;
+                }
+            }
+
+            if let Some(ref mut cache) = cache {
+                cache.finish(*copied).await?;
+            }
+        }
+
+        // the copies use explicit offsets, so the file cursor needs to be moved manually.
+        self.file.seek(io::SeekFrom::Start(start + *copied)).await?;
+        self.finish_download().await?;
+
+        drop(permit);
+        Ok(Some(*copied))
+    }
+}
+
+/// Opens the source, returning its length and whether it's a regular file.
+async fn open_source(path: &Path) -> io::Result<(tokio::fs::File, u64, bool)> {
+    let src = tokio::fs::File::open(path).await?;
+    let metadata = src.metadata().await?;
+    Ok((src, metadata.len(), metadata.is_file()))
+}
+
+#[cfg(target_os = "linux")]
+async fn run_blocking<T, F>(f: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(io::Error::other)?
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::io;
+    use std::os::fd::AsRawFd;
+
+    pub(super) fn ficlone(src: &std::fs::File, dst: &std::fs::File) -> io::Result<()> {
+        // SAFETY: both file descriptors are valid for the duration of the call.
+        let result = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) };
+
+        match result {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    pub(super) fn copy_file_range(
+        src: &std::fs::File,
+        src_offset: u64,
+        dst: &std::fs::File,
+        dst_offset: u64,
+        len: usize,
+    ) -> io::Result<usize> {
+        let mut off_in = src_offset as libc::loff_t;
+        let mut off_out = dst_offset as libc::loff_t;
+
+        // SAFETY: both file descriptors are valid for the duration of the call, and the
+        // offsets point to valid, local integers.
+        let result = unsafe {
+            libc::copy_file_range(
+                src.as_raw_fd(),
+                &mut off_in,
+                dst.as_raw_fd(),
+                &mut off_out,
+                len,
+                0,
+            )
+        };
+
+        match result {
+            -1 => Err(io::Error::last_os_error()),
+            copied => Ok(copied as usize),
+        }
+    }
+
+    /// Whether the error from [`ficlone`] means that the files can't share extents (different
+    /// filesystems, or one that doesn't support reflinks), and the copy should fall back to
+    /// [`copy_file_range`]. Anything else is a real I/O error.
+    pub(super) fn is_clone_unsupported(error: &io::Error) -> bool {
+        matches!(
+            error.raw_os_error(),
+            Some(libc::EOPNOTSUPP | libc::EXDEV | libc::EINVAL | libc::ENOTTY)
+        )
+    }
+
+    /// Whether the error from [`copy_file_range`] means that the kernel can't copy between
+    /// these files, and a userspace copy should be used.
+    pub(super) fn is_unsupported(error: &io::Error) -> bool {
+        matches!(
+            error.raw_os_error(),
+            Some(libc::EXDEV | libc::ENOSYS | libc::EOPNOTSUPP | libc::EINVAL | libc::EBADF)
+        )
+    }
+}
+
+/// Converts a `file://` URL into a local path.
+///
+/// Both `file:///path` and `file://localhost/path` are accepted, along with the short
+/// `file:/path` form. Percent-encoded characters are decoded.
+pub fn file_url_to_path(url: &str) -> io::Result<PathBuf> {
+    let invalid = |message: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid file URL '{url}': {message}"),
+        )
+    };
+
+    let rest = match url.split_once(':') {
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("file") => rest,
+        _ => return Err(invalid("expected a 'file:' scheme")),
+    };
+
+    let path = match rest.strip_prefix("//") {
+        Some(authority_and_path) => {
+            let (host, path) = match authority_and_path.find('/') {
+                Some(idx) => authority_and_path.split_at(idx),
+                None => (authority_and_path, ""),
+            };
+
+            if !host.is_empty() && !host.eq_ignore_ascii_case("localhost") {
+                return Err(invalid("only local files are supported"));
+            }
+
+            path
+        }
+        None => rest,
+    };
+
+    // queries + fragments aren't a part of the path
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+
+    if !path.starts_with('/') {
+        return Err(invalid("expected an absolute path"));
+    }
+
+    let decoded = crate::encoding::percent_decode(path);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        Ok(PathBuf::from(std::ffi::OsString::from_vec(decoded)))
+    }
+
+    #[cfg(not(unix))]
+    {
+        let decoded = String::from_utf8(decoded).map_err(|_| invalid("path isn't valid UTF-8"))?;
+
+        // 'file:///C:/dir' -> 'C:/dir'
+        let decoded = match decoded.as_bytes() {
+            [b'/', drive, b':', ..] if drive.is_ascii_alphabetic() => &decoded[1..],
+            _ => &decoded,
+        };
+
+        Ok(PathBuf::from(decoded))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
+
+    use crate::{Delete, DlErrorKind, DlFile, PauseHandle};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("dl-file-copy-{name}-{}", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn rejects_sources_over_the_limit() {
+        let (src, dst) = (temp_path("large-src"), temp_path("large-dst"));
+        std::fs::write(&src, [1; 100]).unwrap();
+
+        let mut file = DlFile::builder(dst.clone())
+            .max_bytes(99)
+            .open_overwrite()
+            .await
+            .unwrap();
+
+        let error = file.copy_from_path(&src).await.unwrap_err();
+        drop(file);
+        std::fs::remove_file(&src).unwrap();
+
+        assert!(matches!(
+            error.kind(),
+            DlErrorKind::TooLarge {
+                limit: 99,
+                content_length: Some(100),
+            }
+        ));
+        // deleted on drop, since the download is too large
+        assert!(!dst.exists());
+    }
+
+    #[tokio::test]
+    async fn pauses_and_copies_the_length_when_opened() {
+        let (src, dst) = (temp_path("grow-src"), temp_path("grow-dst"));
+        std::fs::write(&src, [1; 1000]).unwrap();
+
+        let pause = PauseHandle::new();
+        pause.pause();
+
+        let mut file = DlFile::builder(dst.clone())
+            .pause_handle(pause.clone())
+            .max_bytes(1000)
+            .delete(Delete::No)
+            .open_overwrite()
+            .await
+            .unwrap();
+
+        // not reflinked, since the file isn't empty
+        file.write_all(b"prefix").await.unwrap();
+
+        let grow = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let len_while_paused = std::fs::metadata(&dst).unwrap().len();
+
+            let mut src = std::fs::OpenOptions::new().append(true).open(&src).unwrap();
+            std::io::Write::write_all(&mut src, &[2; 1000]).unwrap();
+            pause.resume();
+
+            len_while_paused
+        };
+
+        let (copied, len_while_paused) = tokio::join!(file.copy_from_path(&src), grow);
+        let contents = std::fs::read(&dst).unwrap();
+        drop(file);
+        std::fs::remove_file(&src).unwrap();
+        std::fs::remove_file(&dst).unwrap();
+
+        assert_eq!(len_while_paused, 6);
+        assert_eq!(copied.unwrap(), 1000);
+        assert_eq!(contents.len(), 1006);
+        assert!(contents[6..].iter().all(|byte| *byte == 1));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn falls_back_to_reading_pipes() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let (src, dst) = (temp_path("fifo-src"), temp_path("fifo-dst"));
+        let c_src = std::ffi::CString::new(src.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_src.as_ptr(), 0o600) }, 0);
+
+        let writer = {
+            let src = src.clone();
+            std::thread::spawn(move || std::fs::write(src, b"from a pipe").unwrap())
+        };
+
+        let mut file = DlFile::builder(dst.clone())
+            .delete(Delete::No)
+            .open_overwrite()
+            .await
+            .unwrap();
+
+        let copied = file.copy_from_path(&src).await.unwrap();
+        writer.join().unwrap();
+
+        let contents = std::fs::read(&dst).unwrap();
+        drop(file);
+        std::fs::remove_file(&src).unwrap();
+        std::fs::remove_file(&dst).unwrap();
+
+        assert_eq!(copied, 11);
+        assert_eq!(contents, b"from a pipe");
+    }
+}
@@ -1,131 +1,450 @@
+use std::collections::VecDeque;
 use std::io;
 use std::path::Path;
 use std::pin::Pin;
 use std::task::{ready, Context, Poll};
 
 use bytes::Buf;
+use futures::future::BoxFuture;
 use futures::Stream;
 use tokio::fs::File;
 use tokio::io::AsyncWrite;
-use tokio::sync::SemaphorePermit;
 
+use crate::cache::CacheDropper;
+use crate::pause::PauseHandle;
+use crate::permit::PermitSlot;
 use crate::progress::DlProgress;
-use crate::DlFile;
+use crate::{DlErrorKind, DlFile, Durability};
+
+/// The default number of bytes coalesced from the stream before writing. See
+/// [`DlFileBuilder::write_buffer`].
+///
+/// [`DlFileBuilder::write_buffer`]: crate::DlFileBuilder::write_buffer
+pub(crate) const DEFAULT_WRITE_BUFFER: usize = 256 * 1024;
+
+/// The max number of chunks written by a single vectored write.
+const MAX_IO_SLICES: usize = 64;
 
 pin_project_lite::pin_project! {
-    pub(super) struct DownloadDriver<'a, S: Stream<Item = io::Result<B>>, B: Buf> {
-        permit: Option<SemaphorePermit<'a>>,
+    /// Drives a stream into any writer, which is a [`File`] for [`DlFile`]s.
+    pub(crate) struct DownloadDriver<'a, S: Stream<Item = io::Result<B>>, B: Buf, W> {
+        permit: PermitSlot,
+        pause: Option<PauseHandle>,
+        paused: bool,
         path: &'a Path,
         stream: Option<Pin<&'a mut S>>,
-        current_buf: Option<B>,
+        pending: VecDeque<B>,
+        pending_len: usize,
+        write_buffer: usize,
+        stream_error: Option<io::Error>,
         progress: Option<&'a mut dyn DlProgress>,
-        file: Pin<&'a mut File>,
+        writer: Pin<&'a mut W>,
+        cache: Option<CacheDropper>,
+        sync: Option<BoxFuture<'static, io::Result<()>>>,
+        max_bytes: Option<u64>,
+        offset: u64,
         bytes_copied: u64,
     }
 }
 
-impl<'a, S, B> DownloadDriver<'a, S, B>
+/// The settings a [`DownloadDriver`] needs, borrowed from a [`DlFile`] or a [`DlSink`].
+///
+/// [`DlSink`]: crate::DlSink
+pub(crate) struct DriverParts<'a> {
+    /// The path (or name) downloads are reported with.
+    pub(crate) path: &'a Path,
+    pub(crate) permit: PermitSlot,
+    pub(crate) pause: Option<PauseHandle>,
+    pub(crate) progress: Option<&'a mut dyn DlProgress>,
+    pub(crate) max_bytes: Option<u64>,
+    pub(crate) write_buffer: usize,
+}
+
+impl<'a, S, B> DownloadDriver<'a, S, B, File>
 where
     S: Stream<Item = io::Result<B>>,
     B: Buf,
 {
+    /// `offset` is where in the file the stream starts (when resuming a download), which
+    /// progress and the size limit are relative to.
+    ///
+    /// Waits for permits from the semaphore, and fails if it's closed.
     #[inline]
     pub(super) async fn new<P: AsRef<Path>>(
         file: &'a mut DlFile<P>,
         stream: Pin<&'a mut S>,
         size: Option<u64>,
-    ) -> Self {
-        let permit = match file.semaphore {
-            Some(ref semaphore) => Some(semaphore.acquire().await.unwrap()),
-            None => None,
-        };
+        offset: u64,
+    ) -> io::Result<Self> {
+        let cache = CacheDropper::new(&mut file.file, file.page_cache).await?;
 
-        if let Some(ref mut prog) = file.progress {
-            prog.start(file.path.as_ref(), size);
-        }
+        // the sync doesn't run until it's polled, after the download.
+        let sync = match file.durability {
+            Durability::Sync => Some(crate::sync::sync_file_and_parent(
+                &file.file,
+                file.path.as_ref(),
+            )?),
+            Durability::Flush => None,
+        };
 
-        Self {
+        let parts = DriverParts {
             path: file.path.as_ref(),
-            file: Pin::new(&mut file.file),
-            bytes_copied: 0,
-            permit,
-            stream: Some(stream),
-            current_buf: None,
+            permit: file.permit_slot(size),
+            pause: file.pause.clone(),
             progress: match file.progress {
                 Some(ref mut prog) => Some(&mut *prog),
                 None => None,
             },
+            max_bytes: file.max_bytes,
+            write_buffer: file.write_buffer,
+        };
+
+        let mut driver =
+            Self::with_writer(parts, Pin::new(&mut file.file), stream, size, offset).await?;
+
+        driver.cache = cache;
+        driver.sync = sync;
+
+        Ok(driver)
+    }
+}
+
+impl<'a, S, B, W> DownloadDriver<'a, S, B, W>
+where
+    S: Stream<Item = io::Result<B>>,
+    B: Buf,
+    W: AsyncWrite,
+{
+    /// Downloads into any writer, which is only flushed at the end.
+    ///
+    /// Waits for permits from the semaphore, and fails if it's closed.
+    pub(crate) async fn with_writer(
+        mut parts: DriverParts<'a>,
+        writer: Pin<&'a mut W>,
+        stream: Pin<&'a mut S>,
+        size: Option<u64>,
+        offset: u64,
+    ) -> io::Result<Self> {
+        parts.permit.acquire().await?;
+
+        if let Some(ref mut prog) = parts.progress {
+            prog.start(parts.path, size.map(|size| offset + size));
+
+            if offset > 0 {
+                prog.update(parts.path, offset);
+            }
         }
+
+        Ok(Self {
+            path: parts.path,
+            writer,
+            cache: None,
+            sync: None,
+            max_bytes: parts.max_bytes,
+            offset,
+            bytes_copied: 0,
+            permit: parts.permit,
+            pause: parts.pause,
+            paused: false,
+            stream: Some(stream),
+            pending: VecDeque::new(),
+            pending_len: 0,
+            write_buffer: parts.write_buffer,
+            stream_error: None,
+            progress: parts.progress,
+        })
     }
 }
 
-impl<S, B> std::future::Future for DownloadDriver<'_, S, B>
+impl<S, B, W> DownloadDriver<'_, S, B, W>
 where
     S: Stream<Item = io::Result<B>>,
     B: Buf,
 {
-    type Output = io::Result<u64>;
+    /// The number of bytes written so far, which is needed for errors.
+    #[inline]
+    pub(super) fn bytes_copied(&self) -> u64 {
+        self.bytes_copied
+    }
+}
+
+impl<S, B, W> std::future::Future for DownloadDriver<'_, S, B, W>
+where
+    S: Stream<Item = io::Result<B>>,
+    B: Buf,
+    W: AsyncWrite,
+{
+    type Output = Result<u64, DlErrorKind>;
 
     #[inline]
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
 
         'outer: loop {
-            // work towards exhausting the current buffer
-            if let Some(ref mut current) = this.current_buf {
-                'current_buf: loop {
-                    let written = ready!(this.file.as_mut().poll_write(cx, current.chunk()))?;
+            // checked before every write, since a stream that's always ready and a writer that
+            // never returns `Pending` (i.e. a `Vec`) would otherwise never leave this loop.
+            if let Some(ref pause) = this.pause {
+                ready!(pause.poll_paused(
+                    cx,
+                    this.paused,
+                    this.permit,
+                    this.progress.as_deref_mut(),
+                    this.path
+                ));
+            }
 
-                    if written > 0 {
-                        current.advance(written);
-                        *this.bytes_copied += written as u64;
+            ready!(this.permit.poll_acquire(cx))?;
 
-                        if let Some(ref mut prog) = this.progress {
-                            prog.update(this.path, *this.bytes_copied);
-                        }
-                    }
+            let mut stream_pending = false;
 
-                    if !current.has_remaining() {
-                        *this.current_buf = None;
-                        break 'current_buf;
-                    }
-                }
-            }
-
-            // if empty, poll more bytes from the stream
+            // coalesce chunks from the stream until the write buffer is full
             if let Some(ref mut stream) = this.stream {
-                'poll_stream: loop {
-                    let chunk = match ready!(stream.as_mut().poll_next(cx)) {
-                        Some(result) => result?,
-                        None => {
+                while *this.pending_len < (*this.write_buffer).max(1) {
+                    let chunk = match stream.as_mut().poll_next(cx) {
+                        Poll::Ready(Some(Ok(chunk))) => chunk,
+                        // write what was received before the error first
+                        Poll::Ready(Some(Err(error))) if !this.pending.is_empty() => {
+                            *this.stream_error = Some(error);
+                            *this.stream = None;
+                            break;
+                        }
+                        Poll::Ready(Some(Err(error))) => return Poll::Ready(Err(error.into())),
+                        Poll::Ready(None) => {
                             *this.stream = None;
-                            break 'outer;
+                            break;
+                        }
+                        Poll::Pending => {
+                            stream_pending = true;
+                            break;
                         }
                     };
 
                     // skip empty chunks
                     if !chunk.has_remaining() {
-                        continue 'poll_stream;
+                        continue;
                     }
 
-                    // set the current buf, and start back at the top to start writing
-                    *this.current_buf = Some(chunk);
-                    continue 'outer;
+                    // check before writing any of the chunk, so nothing past the limit is written
+                    if let Some(limit) = *this.max_bytes {
+                        let buffered = *this.pending_len + chunk.remaining();
+
+                        if *this.offset + *this.bytes_copied + buffered as u64 > limit {
+                            return Poll::Ready(Err(DlErrorKind::TooLarge {
+                                limit,
+                                content_length: None,
+                            }));
+                        }
+                    }
+
+                    *this.pending_len += chunk.remaining();
+                    this.pending.push_back(chunk);
+                }
+            }
+
+            if this.pending.is_empty() {
+                if let Some(error) = this.stream_error.take() {
+                    return Poll::Ready(Err(error.into()));
+                }
+
+                match stream_pending {
+                    true => return Poll::Pending,
+                    false => break 'outer,
                 }
             }
 
-            break 'outer;
+            if let Some(ref mut cache) = this.cache {
+                ready!(cache.poll_written(cx, *this.bytes_copied))?;
+            }
+
+            // the buffer is full, or the stream is waiting/done, so write what's buffered
+            let written = ready!(poll_write_pending(this.writer.as_mut(), cx, this.pending))?;
+
+            if written == 0 {
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::WriteZero).into()));
+            }
+
+            advance_pending(this.pending, written);
+            *this.pending_len -= written;
+            *this.bytes_copied += written as u64;
+
+            if let Some(ref mut prog) = this.progress {
+                prog.update(this.path, *this.offset + *this.bytes_copied);
+            }
         }
 
         // if we made it here, there's no stream left and no current chunk, so we need to flush.
-        ready!(this.file.as_mut().poll_flush(cx))?;
+        ready!(this.writer.as_mut().poll_flush(cx))?;
+
+        if let Some(ref mut cache) = this.cache {
+            ready!(cache.poll_finish(cx, *this.bytes_copied))?;
+        }
+
+        if let Some(ref mut sync) = this.sync {
+            ready!(sync.as_mut().poll(cx))?;
+        }
 
         if let Some(ref mut prog) = this.progress {
             prog.finished(this.path);
         }
 
-        let _ = this.permit.take();
+        this.permit.release();
 
         Poll::Ready(Ok(*this.bytes_copied))
     }
 }
+
+/// Writes as much of the buffered chunks as possible, with a single vectored write if the
+/// writer supports it.
+fn poll_write_pending<B: Buf, W: AsyncWrite>(
+    writer: Pin<&mut W>,
+    cx: &mut Context<'_>,
+    pending: &VecDeque<B>,
+) -> Poll<io::Result<usize>> {
+    if !writer.is_write_vectored() {
+        return match pending.front() {
+            Some(front) => writer.poll_write(cx, front.chunk()),
+            None => Poll::Ready(Ok(0)),
+        };
+    }
+
+    let mut slices = [io::IoSlice::new(&[]); MAX_IO_SLICES];
+    let mut filled = 0;
+
+    for buf in pending {
+        filled += buf.chunks_vectored(&mut slices[filled..]);
+
+        if filled == MAX_IO_SLICES {
+            break;
+        }
+    }
+
+    writer.poll_write_vectored(cx, &slices[..filled])
+}
+
+/// Drops `written` bytes from the front of the buffered chunks.
+fn advance_pending<B: Buf>(pending: &mut VecDeque<B>, mut written: usize) {
+    while written > 0 {
+        let Some(front) = pending.front_mut() else {
+            return;
+        };
+
+        let count = front.remaining().min(written);
+        front.advance(count);
+        written -= count;
+
+        if !front.has_remaining() {
+            pending.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use bytes::Bytes;
+    use tokio::io::AsyncWriteExt;
+
+    use crate::{Delete, DlErrorKind, DlFile, PartialFile};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("dl-file-driver-{name}-{}", std::process::id()))
+    }
+
+    fn chunks(chunks: &[&'static [u8]]) -> impl futures::Stream<Item = std::io::Result<Bytes>> {
+        let chunks: Vec<_> = chunks
+            .iter()
+            .map(|chunk| Ok(Bytes::from_static(chunk)))
+            .collect();
+        futures::stream::iter(chunks)
+    }
+
+    #[tokio::test]
+    async fn rejects_a_content_length_over_the_limit() {
+        let path = temp_path("too-large");
+        let mut file = DlFile::builder(&path)
+            .max_bytes(8)
+            .open_overwrite()
+            .await
+            .unwrap();
+
+        let error = file
+            .download_from_io_stream(Some(11), chunks(&[b"hello", b" world"]))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            error.kind(),
+            DlErrorKind::TooLarge {
+                limit: 8,
+                content_length: Some(11),
+            }
+        ));
+        assert_eq!(error.bytes_written(), 0);
+        assert_eq!(error.partial_file(), PartialFile::Deleted);
+
+        drop(file);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn stops_streams_before_they_pass_the_limit() {
+        let path = temp_path("grows");
+        let mut file = DlFile::builder(&path)
+            .max_bytes(8)
+            .write_buffer(0)
+            .delete(Delete::No)
+            .open_overwrite()
+            .await
+            .unwrap();
+
+        let error = file
+            .download_from_io_stream(None, chunks(&[b"hello", b" world"]))
+            .await
+            .unwrap_err();
+
+        // waits for the write tokio still had in flight when the download failed
+        file.flush().await.unwrap();
+        let contents = std::fs::read(&path).unwrap();
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            error.kind(),
+            DlErrorKind::TooLarge {
+                limit: 8,
+                content_length: None,
+            }
+        ));
+        assert_eq!(error.bytes_written(), 5);
+        assert_eq!(error.partial_file(), PartialFile::Kept);
+        assert_eq!(contents, b"hello");
+    }
+
+    #[tokio::test]
+    async fn counts_the_resume_offset_against_the_limit() {
+        let path = temp_path("resumed");
+        let mut file = DlFile::builder(&path)
+            .max_bytes(8)
+            .delete(Delete::No)
+            .open_overwrite()
+            .await
+            .unwrap();
+
+        let error = file
+            .download_from_io_stream_at(5, Some(5), chunks(&[b"world"]))
+            .await
+            .unwrap_err();
+
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            error.kind(),
+            DlErrorKind::TooLarge {
+                limit: 8,
+                content_length: Some(10),
+            }
+        ));
+    }
+}
@@ -0,0 +1,512 @@
+use std::path::{Path, PathBuf};
+use std::{error, fmt, io};
+
+use bytes::Bytes;
+use http::StatusCode;
+
+/// An error from downloading into a [`DlFile`], with context about what failed, and what state
+/// the download was left in.
+///
+/// Converts into an [`io::Error`] (with an equivalent [`io::ErrorKind`]) for compatibility,
+/// with the [`DlError`] as the inner error, so it can be recovered with
+/// [`io::Error::downcast`].
+///
+/// [`DlFile`]: crate::DlFile
+#[derive(Debug)]
+pub struct DlError {
+    kind: DlErrorKind,
+    path: PathBuf,
+    bytes_written: u64,
+    partial_file: PartialFile,
+}
+
+/// The underlying cause of a [`DlError`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DlErrorKind {
+    /// The server responded with a status that isn't accepted, with a snippet of the body of
+    /// the response if [`DlFileBuilder::error_body_snippet`] was set.
+    ///
+    /// [`DlFileBuilder::error_body_snippet`]: crate::DlFileBuilder::error_body_snippet
+    Status {
+        status: StatusCode,
+        body: Option<Bytes>,
+    },
+    /// The server responded with `206 Partial Content`, but the range doesn't start where the
+    /// file is being written.
+    UnexpectedRange {
+        expected_start: u64,
+        range_start: Option<u64>,
+    },
+    /// The server responded with `206 Partial Content` for a range that stops before the end
+    /// of the file, so the file would be truncated.
+    IncompleteRange {
+        range_end: u64,
+        complete_length: u64,
+    },
+    /// The download is larger than [`DlFileBuilder::max_bytes`], either from the announced
+    /// `content_length`, or from the stream growing past the limit (in which case,
+    /// `content_length` is `None`).
+    ///
+    /// [`DlFileBuilder::max_bytes`]: crate::DlFileBuilder::max_bytes
+    TooLarge {
+        limit: u64,
+        content_length: Option<u64>,
+    },
+    /// The number of bytes written doesn't match the expected size, i.e. when finishing a
+    /// [`DlFileWriter`] that was given an estimated size.
+    ///
+    /// [`DlFileWriter`]: crate::DlFileWriter
+    SizeMismatch { expected: u64, written: u64 },
+    /// The download was aborted, i.e. with [`DownloadHandle::abort`].
+    ///
+    /// [`DownloadHandle::abort`]: crate::DownloadHandle::abort
+    Aborted,
+    /// An error from reqwest, either sending the request or streaming the body.
+    #[cfg(feature = "reqwest")]
+    Reqwest(reqwest::Error),
+    /// Any other I/O error, either from the file or a non-reqwest source.
+    Io(io::Error),
+}
+
+/// What happens to the partially written file after a failed download, according to the
+/// [`Delete`] policy of the [`DlFile`] when the error happened.
+///
+/// This is what will happen once the [`DlFile`] is dropped, since the file can still be
+/// [`reset`] and retried until then.
+///
+/// [`Delete`]: crate::Delete
+/// [`DlFile`]: crate::DlFile
+/// [`reset`]: crate::DlFile::reset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PartialFile {
+    Kept,
+    Deleted,
+}
+
+impl DlError {
+    #[inline]
+    pub(crate) fn new(
+        kind: DlErrorKind,
+        path: PathBuf,
+        bytes_written: u64,
+        partial_file: PartialFile,
+    ) -> Self {
+        Self {
+            kind,
+            path,
+            bytes_written,
+            partial_file,
+        }
+    }
+
+    #[cfg(feature = "reqwest")]
+    #[inline]
+    pub(crate) fn with_bytes_written(mut self, bytes_written: u64) -> Self {
+        self.bytes_written = bytes_written;
+        self
+    }
+
+    #[inline]
+    pub fn kind(&self) -> &DlErrorKind {
+        &self.kind
+    }
+
+    #[inline]
+    pub fn into_kind(self) -> DlErrorKind {
+        self.kind
+    }
+
+    /// The path of the file being downloaded.
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The number of bytes written into the file by the failed download, before it failed.
+    #[inline]
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    #[inline]
+    pub fn partial_file(&self) -> PartialFile {
+        self.partial_file
+    }
+
+    /// The HTTP status that caused the error, if there was one.
+    #[inline]
+    pub fn status(&self) -> Option<StatusCode> {
+        match self.kind {
+            DlErrorKind::Status { status, .. } => Some(status),
+            DlErrorKind::UnexpectedRange { .. } | DlErrorKind::IncompleteRange { .. } => {
+                Some(StatusCode::PARTIAL_CONTENT)
+            }
+            #[cfg(feature = "reqwest")]
+            DlErrorKind::Reqwest(ref error) => error.status(),
+            DlErrorKind::TooLarge { .. }
+            | DlErrorKind::SizeMismatch { .. }
+            | DlErrorKind::Aborted
+            | DlErrorKind::Io(_) => None,
+        }
+    }
+
+    /// Whether the failure is likely to be transient, and the download worth retrying.
+    pub fn is_retryable(&self) -> bool {
+        match self.kind {
+            DlErrorKind::Status { status, .. } => is_retryable_status(status),
+            DlErrorKind::UnexpectedRange { .. }
+            | DlErrorKind::IncompleteRange { .. }
+            | DlErrorKind::TooLarge { .. }
+            | DlErrorKind::SizeMismatch { .. }
+            | DlErrorKind::Aborted => false,
+            #[cfg(feature = "reqwest")]
+            DlErrorKind::Reqwest(ref error) => match error.status() {
+                Some(status) => is_retryable_status(status),
+                None => {
+                    error.is_timeout()
+                        || error.is_connect()
+                        || error.is_body()
+                        || has_retryable_io_source(error)
+                }
+            },
+            DlErrorKind::Io(ref error) => is_retryable_io_error(error),
+        }
+    }
+
+    /// The closest [`io::ErrorKind`] to this error.
+    pub fn io_error_kind(&self) -> io::ErrorKind {
+        match self.kind {
+            DlErrorKind::Status { status, .. } => status_error_kind(status),
+            DlErrorKind::UnexpectedRange { .. } | DlErrorKind::IncompleteRange { .. } => {
+                io::ErrorKind::InvalidData
+            }
+            DlErrorKind::TooLarge { .. } => io::ErrorKind::FileTooLarge,
+            DlErrorKind::SizeMismatch { expected, written } if written < expected => {
+                io::ErrorKind::UnexpectedEof
+            }
+            DlErrorKind::SizeMismatch { .. } => io::ErrorKind::InvalidData,
+            DlErrorKind::Aborted => io::ErrorKind::Interrupted,
+            #[cfg(feature = "reqwest")]
+            DlErrorKind::Reqwest(ref error) => reqwest_error_kind(error),
+            DlErrorKind::Io(ref error) => error.kind(),
+        }
+    }
+}
+
+impl DlErrorKind {
+    /// Pulls the original reqwest error back out of an [`io::Error`], if the error came from
+    /// the body stream of a [`reqwest::Response`].
+    pub(crate) fn from_io(error: io::Error) -> Self {
+        #[cfg(feature = "reqwest")]
+        if error
+            .get_ref()
+            .is_some_and(|inner| inner.is::<reqwest::Error>())
+        {
+            return match error.into_inner().map(|inner| inner.downcast()) {
+                Some(Ok(error)) => Self::Reqwest(*error),
+                _ => unreachable!("inner error was checked to be a reqwest::Error"),
+            };
+        }
+
+        Self::Io(error)
+    }
+}
+
+impl From<io::Error> for DlErrorKind {
+    #[inline]
+    fn from(error: io::Error) -> Self {
+        Self::from_io(error)
+    }
+}
+
+impl fmt::Display for DlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "error downloading '{}' (after {} bytes): {}",
+            self.path.display(),
+            self.bytes_written,
+            self.kind
+        )
+    }
+}
+
+impl fmt::Display for DlErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Status { status, .. } => write!(f, "server responded with '{status}'"),
+            Self::UnexpectedRange {
+                expected_start,
+                range_start: Some(range_start),
+            } => write!(
+                f,
+                "expected a partial response starting at byte {expected_start}, got one starting at byte {range_start}"
+            ),
+            Self::UnexpectedRange {
+                expected_start,
+                range_start: None,
+            } => write!(
+                f,
+                "expected a partial response starting at byte {expected_start}, got one without a valid 'Content-Range'"
+            ),
+            Self::IncompleteRange {
+                range_end,
+                complete_length,
+            } => write!(
+                f,
+                "expected a partial response through the end of the file ({complete_length} bytes), got one ending at byte {range_end}"
+            ),
+            Self::TooLarge {
+                limit,
+                content_length: Some(content_length),
+            } => write!(
+                f,
+                "content length of {content_length} bytes is larger than the limit of {limit} bytes"
+            ),
+            Self::TooLarge {
+                limit,
+                content_length: None,
+            } => write!(f, "download grew past the limit of {limit} bytes"),
+            Self::SizeMismatch { expected, written } => write!(
+                f,
+                "expected {expected} bytes, but {written} bytes were written"
+            ),
+            Self::Aborted => f.write_str("download was aborted"),
+            #[cfg(feature = "reqwest")]
+            Self::Reqwest(error) => fmt::Display::fmt(error, f),
+            Self::Io(error) => fmt::Display::fmt(error, f),
+        }
+    }
+}
+
+impl error::Error for DlError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self.kind {
+            DlErrorKind::Status { .. }
+            | DlErrorKind::UnexpectedRange { .. }
+            | DlErrorKind::IncompleteRange { .. }
+            | DlErrorKind::TooLarge { .. }
+            | DlErrorKind::SizeMismatch { .. }
+            | DlErrorKind::Aborted => None,
+            #[cfg(feature = "reqwest")]
+            DlErrorKind::Reqwest(ref error) => Some(error),
+            DlErrorKind::Io(ref error) => Some(error),
+        }
+    }
+}
+
+impl From<DlError> for io::Error {
+    #[inline]
+    fn from(error: DlError) -> Self {
+        io::Error::new(error.io_error_kind(), error)
+    }
+}
+
+#[inline]
+fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::REQUEST_TIMEOUT
+            | StatusCode::TOO_EARLY
+            | StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+#[inline]
+fn is_retryable_io_error(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::TimedOut
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+/// reqwest reports some connection errors (i.e. a truncated body) as decode errors, so the
+/// underlying I/O error needs to be checked.
+#[cfg(feature = "reqwest")]
+fn has_retryable_io_source(error: &reqwest::Error) -> bool {
+    let mut source = error::Error::source(error);
+
+    while let Some(error) = source {
+        if error
+            .downcast_ref::<io::Error>()
+            .is_some_and(is_retryable_io_error)
+        {
+            return true;
+        }
+
+        source = error.source();
+    }
+
+    false
+}
+
+pub(crate) fn status_error_kind(status: StatusCode) -> io::ErrorKind {
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => io::ErrorKind::PermissionDenied,
+        StatusCode::CONFLICT => io::ErrorKind::AlreadyExists,
+        StatusCode::NOT_FOUND | StatusCode::GONE => io::ErrorKind::NotFound,
+        _ if status.is_client_error() => io::ErrorKind::InvalidInput,
+        _ if status.is_server_error() => io::ErrorKind::ConnectionAborted,
+        _ => io::ErrorKind::Other,
+    }
+}
+
+#[cfg(feature = "reqwest")]
+pub(crate) fn reqwest_error_kind(error: &reqwest::Error) -> io::ErrorKind {
+    if let Some(status) = error.status() {
+        status_error_kind(status)
+    } else if error.is_timeout() {
+        io::ErrorKind::TimedOut
+    } else if error.is_connect() {
+        io::ErrorKind::ConnectionAborted
+    } else if error.is_decode() || error.is_body() {
+        io::ErrorKind::InvalidData
+    } else if error.is_request() || error.is_builder() {
+        io::ErrorKind::InvalidInput
+    } else if error.is_redirect() {
+        io::ErrorKind::ConnectionReset
+    } else {
+        io::ErrorKind::Other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::path::PathBuf;
+
+    use http::StatusCode;
+
+    use super::{DlError, DlErrorKind, PartialFile};
+
+    fn error(kind: DlErrorKind) -> DlError {
+        DlError::new(kind, PathBuf::from("file"), 10, PartialFile::Kept)
+    }
+
+    fn status(status: StatusCode) -> DlErrorKind {
+        DlErrorKind::Status { status, body: None }
+    }
+
+    #[test]
+    fn round_trips_through_io_errors() {
+        let kind = DlErrorKind::TooLarge {
+            limit: 5,
+            content_length: Some(10),
+        };
+
+        let io_error = io::Error::from(error(kind));
+        assert_eq!(io_error.kind(), io::ErrorKind::FileTooLarge);
+
+        let error = io_error.downcast::<DlError>().unwrap();
+        assert_eq!(error.path(), PathBuf::from("file"));
+        assert_eq!(error.bytes_written(), 10);
+        assert_eq!(error.partial_file(), PartialFile::Kept);
+        assert!(matches!(
+            error.kind(),
+            DlErrorKind::TooLarge {
+                limit: 5,
+                content_length: Some(10),
+            }
+        ));
+    }
+
+    #[test]
+    fn maps_to_io_error_kinds() {
+        let cases = [
+            (status(StatusCode::NOT_FOUND), io::ErrorKind::NotFound),
+            (
+                status(StatusCode::FORBIDDEN),
+                io::ErrorKind::PermissionDenied,
+            ),
+            (status(StatusCode::BAD_REQUEST), io::ErrorKind::InvalidInput),
+            (
+                status(StatusCode::BAD_GATEWAY),
+                io::ErrorKind::ConnectionAborted,
+            ),
+            (
+                DlErrorKind::SizeMismatch {
+                    expected: 10,
+                    written: 5,
+                },
+                io::ErrorKind::UnexpectedEof,
+            ),
+            (
+                DlErrorKind::SizeMismatch {
+                    expected: 5,
+                    written: 10,
+                },
+                io::ErrorKind::InvalidData,
+            ),
+            (DlErrorKind::Aborted, io::ErrorKind::Interrupted),
+            (
+                io::Error::from(io::ErrorKind::StorageFull).into(),
+                io::ErrorKind::StorageFull,
+            ),
+        ];
+
+        for (kind, expected) in cases {
+            let error = error(kind);
+            assert_eq!(error.io_error_kind(), expected, "{error}");
+        }
+    }
+
+    #[test]
+    fn retries_transient_failures() {
+        let retryable = [
+            status(StatusCode::SERVICE_UNAVAILABLE),
+            status(StatusCode::TOO_MANY_REQUESTS),
+            io::Error::from(io::ErrorKind::ConnectionReset).into(),
+            io::Error::from(io::ErrorKind::UnexpectedEof).into(),
+        ];
+
+        let permanent = [
+            status(StatusCode::NOT_FOUND),
+            status(StatusCode::NOT_IMPLEMENTED),
+            io::Error::from(io::ErrorKind::PermissionDenied).into(),
+            DlErrorKind::TooLarge {
+                limit: 5,
+                content_length: None,
+            },
+            DlErrorKind::UnexpectedRange {
+                expected_start: 5,
+                range_start: Some(0),
+            },
+            DlErrorKind::Aborted,
+        ];
+
+        for kind in retryable {
+            let error = error(kind);
+            assert!(error.is_retryable(), "{error}");
+        }
+
+        for kind in permanent {
+            let error = error(kind);
+            assert!(!error.is_retryable(), "{error}");
+        }
+    }
+
+    #[cfg(feature = "reqwest")]
+    #[test]
+    fn recovers_reqwest_errors_from_io_errors() {
+        let reqwest_error = reqwest::Client::new().get("not a url").build().unwrap_err();
+
+        let kind = DlErrorKind::from(io::Error::other(reqwest_error));
+        assert!(matches!(kind, DlErrorKind::Reqwest(ref error) if error.is_builder()));
+
+        let kind = DlErrorKind::from(io::Error::other("not reqwest"));
+        assert!(matches!(kind, DlErrorKind::Io(_)));
+    }
+}
@@ -0,0 +1,260 @@
+//! Sending (and resending) reqwest requests into a [`DlFile`], resuming with range requests
+//! where possible.
+use std::path::Path;
+
+use http::header::{IF_RANGE, RANGE};
+use http::StatusCode;
+use reqwest::{Client, IntoUrl, RequestBuilder};
+
+use crate::source::SourceMetadata;
+use crate::{DlError, DlErrorKind, DlFile, DlSource};
+
+/// Tracks how much of a file has been fetched so far, and the validator of the data, so
+/// that a later request can resume from the same offset.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct FetchState {
+    /// The number of bytes in the file.
+    pub(crate) offset: u64,
+    /// A validator (strong `ETag` or `Last-Modified`) of the data written so far, sent as
+    /// `If-Range` when resuming.
+    validator: Option<String>,
+}
+
+impl FetchState {
+    /// Picks the validator to send as `If-Range`. Weak `ETag`s can't be used for ranges.
+    fn validator_of(metadata: &SourceMetadata) -> Option<String> {
+        let strong_etag = metadata
+            .etag
+            .as_ref()
+            .filter(|etag| !etag.starts_with("W/"));
+
+        strong_etag.or(metadata.last_modified.as_ref()).cloned()
+    }
+
+    /// Whether a response continues the data fetched so far: a partial response, for data
+    /// with the same validator. `If-Range` should guarantee this, but not every server (or
+    /// every mirror) honors it.
+    fn can_resume_with(&self, metadata: &SourceMetadata) -> bool {
+        metadata.status == Some(StatusCode::PARTIAL_CONTENT)
+            && self.validator.is_some()
+            && Self::validator_of(metadata) == self.validator
+    }
+}
+
+impl<P: AsRef<Path>> DlFile<P> {
+    /// Downloads a URL with a `GET` request. See [`download_with`] for details.
+    ///
+    /// [`download_with`]: Self::download_with
+    pub async fn download_url<U>(&mut self, client: &Client, url: U) -> Result<u64, DlError>
+    where
+        U: IntoUrl,
+    {
+        match url.into_url() {
+            Ok(url) => self.download_with(|| client.get(url.clone())).await,
+            Err(error) => Err(self.download_error(DlErrorKind::Reqwest(error), 0).await),
+        }
+    }
+
+    /// Downloads the response to a request built by `make_request`, which is called again
+    /// for each retry, so it can rebuild things like signed URLs or auth headers.
+    ///
+    /// The file is reset before the first request. Retryable errors are retried according
+    /// to the [`RetryPolicy`] set with [`DlFileBuilder::retry_policy`] (by default, never),
+    /// resuming with a range request from the bytes already written when the server sent a
+    /// validator (`ETag` or `Last-Modified`) for the data. Otherwise, the file is reset and
+    /// downloaded from the start.
+    ///
+    /// Returns the total number of bytes in the file.
+    ///
+    /// [`RetryPolicy`]: crate::RetryPolicy
+    /// [`DlFileBuilder::retry_policy`]: crate::DlFileBuilder::retry_policy
+    pub async fn download_with<F>(&mut self, mut make_request: F) -> Result<u64, DlError>
+    where
+        F: FnMut() -> RequestBuilder,
+    {
+        let mut state = FetchState::default();
+
+        if let Err(error) = self.reset().await {
+            return Err(self.download_error(error, 0).await);
+        }
+
+        let mut retry = 0;
+
+        loop {
+            match self.fetch_into(make_request(), &mut state).await {
+                Ok(bytes) => return Ok(bytes),
+                Err(error) if error.is_retryable() && retry < self.retry_policy.max_retries => {
+                    tokio::time::sleep(self.retry_policy.backoff(retry)).await;
+                    retry += 1;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Sends `request`, and downloads the response into the file.
+    ///
+    /// If some of the file was already fetched, a range request is made for the rest, with
+    /// `If-Range` so the server only sends a partial response if the data is unchanged.
+    /// Otherwise (or without a validator to send), the file is reset and the whole response
+    /// is written. A partial response is only resumed from if its validator matches, and if
+    /// it doesn't, the whole file is requested again.
+    ///
+    /// `state` is updated with the bytes written, even on errors, so the next request can
+    /// resume where this one left off.
+    ///
+    /// Nothing is sent while the download is paused.
+    pub(crate) async fn fetch_into(
+        &mut self,
+        mut request: RequestBuilder,
+        state: &mut FetchState,
+    ) -> Result<u64, DlError> {
+        if let Some(pause) = self.pause.clone() {
+            pause.resumed().await;
+        }
+
+        // kept to request the whole file again, if a partial response turns out to be for
+        // different data.
+        let mut full_request = None;
+
+        if state.offset > 0 {
+            match state.validator {
+                Some(ref validator) => {
+                    full_request = request.try_clone();
+                    request = request
+                        .header(RANGE, format!("bytes={}-", state.offset))
+                        .header(IF_RANGE, validator);
+                }
+                None => self.reset_fetch(state).await?,
+            }
+        }
+
+        let mut response = self.send(request, state.offset).await?;
+        let mut metadata = response.metadata();
+
+        // the server ignored the range, or the data changed, so start from scratch.
+        if state.offset > 0 && !state.can_resume_with(&metadata) {
+            self.reset_fetch(state).await?;
+
+            // a partial response for different data is useless. Without a request to resend,
+            // it's rejected by the status check, since it doesn't start at 0.
+            if let (Some(StatusCode::PARTIAL_CONTENT), Some(request)) =
+                (metadata.status, full_request)
+            {
+                response = self.send(request, 0).await?;
+                metadata = response.metadata();
+            }
+        }
+
+        state.validator = FetchState::validator_of(&metadata);
+
+        match self.download_from_source_at(state.offset, response).await {
+            Ok(written) => {
+                state.offset += written;
+                Ok(state.offset)
+            }
+            Err(error) => {
+                state.offset += error.bytes_written();
+                Err(error.with_bytes_written(state.offset))
+            }
+        }
+    }
+
+    async fn send(
+        &mut self,
+        request: RequestBuilder,
+        offset: u64,
+    ) -> Result<reqwest::Response, DlError> {
+        match request.send().await {
+            Ok(response) => Ok(response),
+            Err(error) => Err(self
+                .download_error(DlErrorKind::Reqwest(error), offset)
+                .await),
+        }
+    }
+
+    async fn reset_fetch(&mut self, state: &mut FetchState) -> Result<(), DlError> {
+        if let Err(error) = self.reset().await {
+            return Err(self.download_error(error, state.offset).await);
+        }
+
+        *state = FetchState::default();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use crate::{DlFile, RetryPolicy};
+
+    /// Serves `responses` in order, one per connection, sending back each request received.
+    fn serve(responses: Vec<&'static str>) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file", listener.local_addr().unwrap());
+        let (sender, requests) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..read]);
+                }
+
+                let _ = sender.send(String::from_utf8(request).unwrap().to_lowercase());
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    #[tokio::test]
+    async fn partial_response_with_a_different_validator_restarts() {
+        let (url, requests) = serve(vec![
+            // cut off halfway, which is retried with a range request
+            "HTTP/1.1 200 OK\r\netag: \"a\"\r\ncontent-length: 10\r\nconnection: close\r\n\r\naaaaa",
+            // a server that ignores If-Range, with different data
+            "HTTP/1.1 206 Partial Content\r\netag: \"b\"\r\ncontent-range: bytes 5-9/10\r\n\
+             content-length: 5\r\nconnection: close\r\n\r\nbbbbb",
+            "HTTP/1.1 200 OK\r\netag: \"b\"\r\ncontent-length: 10\r\nconnection: close\r\n\r\nbbbbbbbbbb",
+        ]);
+
+        let path = std::env::temp_dir().join(format!("dl-file-fetch-{}", std::process::id()));
+        let retry_policy = RetryPolicy {
+            initial_backoff: Duration::ZERO,
+            ..RetryPolicy::new(1)
+        };
+
+        let mut file = DlFile::builder(&path)
+            .retry_policy(retry_policy)
+            .open_overwrite()
+            .await
+            .unwrap();
+
+        let bytes = file
+            .download_url(&reqwest::Client::new(), &url)
+            .await
+            .unwrap();
+
+        let requests: Vec<_> = requests.try_iter().collect();
+        let contents = std::fs::read(&path).unwrap();
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(bytes, 10);
+        assert_eq!(contents, b"bbbbbbbbbb");
+        assert_eq!(requests.len(), 3);
+        assert!(requests[1].contains("range: bytes=5-"));
+        assert!(requests[1].contains("if-range: \"a\""));
+        assert!(!requests[2].contains("range:"));
+    }
+}
@@ -0,0 +1,318 @@
+//! Inferring safe file names from download metadata, for downloading into a directory.
+use crate::encoding::percent_decode;
+use crate::source::SourceMetadata;
+
+/// The longest file name (in bytes) most filesystems support.
+const MAX_FILE_NAME_LEN: usize = 255;
+
+/// Picks a file name for a download, from (in order of preference) the `filename*` or
+/// `filename` parameters of the `Content-Disposition` header, or the last segment of the URL
+/// path. The name is sanitized with [`sanitize_filename`].
+pub fn infer_filename(metadata: &SourceMetadata) -> Option<String> {
+    let from_disposition = metadata
+        .content_disposition
+        .as_deref()
+        .and_then(filename_from_content_disposition)
+        .and_then(|name| sanitize_filename(&name));
+
+    from_disposition.or_else(|| {
+        metadata
+            .url
+            .as_deref()
+            .and_then(filename_from_url)
+            .and_then(|name| sanitize_filename(&name))
+    })
+}
+
+/// Extracts the (unsanitized) file name from a `Content-Disposition` header value, preferring
+/// the RFC 5987 encoded `filename*` parameter over `filename`.
+pub fn filename_from_content_disposition(header: &str) -> Option<String> {
+    let mut filename = None;
+    let mut encoded_filename = None;
+
+    // skip the disposition type (i.e. 'attachment')
+    let (_, mut rest) = header.split_once(';')?;
+
+    while let Some((name, value, remaining)) = next_param(rest) {
+        rest = remaining;
+
+        if name.eq_ignore_ascii_case("filename*") {
+            encoded_filename = decode_ext_value(&value);
+        } else if name.eq_ignore_ascii_case("filename") {
+            filename = Some(value);
+        }
+    }
+
+    encoded_filename.or(filename)
+}
+
+/// Parses the next `name=value` parameter, returning it and the rest of the header. Values
+/// can be tokens or quoted strings (which can contain `;`, and backslash escapes).
+fn next_param(input: &str) -> Option<(&str, String, &str)> {
+    let input = input.trim_start_matches(|c: char| c == ';' || c.is_ascii_whitespace());
+    let (name, rest) = input.split_once('=')?;
+    let rest = rest.trim_start();
+
+    match rest.strip_prefix('"') {
+        Some(quoted) => {
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+
+            while let Some((idx, c)) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next().map(|(_, escaped)| escaped)),
+                    '"' => return Some((name.trim(), value, &quoted[idx + 1..])),
+                    _ => value.push(c),
+                }
+            }
+
+            // unterminated quotes, take the rest as the value
+            Some((name.trim(), value, ""))
+        }
+        None => {
+            let (value, rest) = rest.split_once(';').unwrap_or((rest, ""));
+            Some((name.trim(), value.trim().to_owned(), rest))
+        }
+    }
+}
+
+/// Decodes an RFC 5987 `charset'language'percent-encoded` value. Only UTF-8 and ISO-8859-1
+/// are supported, since those are the only charsets the RFC requires.
+fn decode_ext_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let (charset, _language, encoded) = (parts.next()?, parts.next()?, parts.next()?);
+    let decoded = percent_decode(encoded);
+
+    if charset.eq_ignore_ascii_case("utf-8") {
+        String::from_utf8(decoded).ok()
+    } else if charset.eq_ignore_ascii_case("iso-8859-1") {
+        Some(decoded.into_iter().map(char::from).collect())
+    } else {
+        None
+    }
+}
+
+/// Extracts the (unsanitized) last segment of the path of a URL.
+pub fn filename_from_url(url: &str) -> Option<String> {
+    let without_fragment = url.split('#').next()?;
+    let path = without_fragment.split('?').next()?;
+
+    // skip past 'scheme://authority', if there is one
+    let path = match path.split_once("://") {
+        Some((_, after_scheme)) => &after_scheme[after_scheme.find('/')?..],
+        None => path,
+    };
+
+    let segment = path.rsplit('/').find(|segment| !segment.is_empty())?;
+    Some(String::from_utf8_lossy(&percent_decode(segment)).into_owned())
+}
+
+/// Makes a file name safe to join onto a directory.
+///
+/// Only the last path component is kept (so `..` and absolute paths can't escape the
+/// directory), control characters and characters that aren't allowed on Windows are
+/// replaced with `_`, trailing dots and spaces are trimmed, Windows reserved device names
+/// (`CON`, `NUL`, `COM1`, ...) are prefixed with `_`, and the name is truncated to 255 bytes.
+///
+/// Returns `None` if nothing usable is left.
+pub fn sanitize_filename(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or(name);
+
+    let sanitized: String = name
+        .chars()
+        .map(|c| match c {
+            c if c.is_control() => '_',
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c => c,
+        })
+        .collect();
+
+    let sanitized = sanitized.trim_start();
+
+    let mut sanitized = match is_reserved_name(sanitized) {
+        true => format!("_{sanitized}"),
+        false => sanitized.to_owned(),
+    };
+
+    if sanitized.len() > MAX_FILE_NAME_LEN {
+        let mut end = MAX_FILE_NAME_LEN;
+
+        while !sanitized.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        sanitized.truncate(end);
+    }
+
+    // trimmed after truncating, which can leave a new trailing dot or space. This also
+    // empties '.' and '..'.
+    let trimmed_len = sanitized
+        .trim_end_matches(|c: char| c == '.' || c.is_whitespace())
+        .len();
+    sanitized.truncate(trimmed_len);
+
+    match sanitized.is_empty() {
+        true => None,
+        false => Some(sanitized),
+    }
+}
+
+fn is_reserved_name(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+
+    match stem.len() {
+        3 => ["CON", "PRN", "AUX", "NUL"]
+            .iter()
+            .any(|reserved| stem.eq_ignore_ascii_case(reserved)),
+        // compared as bytes, since the 4th byte can be in the middle of a multibyte char.
+        4 => {
+            let (prefix, digit) = stem.as_bytes().split_at(3);
+            (prefix.eq_ignore_ascii_case(b"COM") || prefix.eq_ignore_ascii_case(b"LPT"))
+                && matches!(digit, [b'1'..=b'9'])
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_disposition_prefers_encoded_filename() {
+        let header =
+            "attachment; filename=\"fallback.txt\"; filename*=UTF-8''na%C3%AFve%20file.txt";
+        assert_eq!(
+            filename_from_content_disposition(header).as_deref(),
+            Some("naïve file.txt")
+        );
+
+        let header = "attachment; filename*=iso-8859-1'en'caf%E9.txt";
+        assert_eq!(
+            filename_from_content_disposition(header).as_deref(),
+            Some("café.txt")
+        );
+
+        // unsupported charsets fall back to the plain parameter
+        let header = "attachment; filename*=koi8-r''%C1.txt; filename=plain.txt";
+        assert_eq!(
+            filename_from_content_disposition(header).as_deref(),
+            Some("plain.txt")
+        );
+    }
+
+    #[test]
+    fn content_disposition_quoted_values() {
+        let header = r#"attachment; filename="a;b \"c\".txt"; size=10"#;
+        assert_eq!(
+            filename_from_content_disposition(header).as_deref(),
+            Some(r#"a;b "c".txt"#)
+        );
+
+        let header = "attachment; filename=token.txt; size=10";
+        assert_eq!(
+            filename_from_content_disposition(header).as_deref(),
+            Some("token.txt")
+        );
+
+        assert_eq!(filename_from_content_disposition("inline"), None);
+    }
+
+    #[test]
+    fn sanitize_keeps_only_the_last_component() {
+        assert_eq!(
+            sanitize_filename("../../etc/passwd").as_deref(),
+            Some("passwd")
+        );
+        assert_eq!(
+            sanitize_filename("..\\..\\boot.ini").as_deref(),
+            Some("boot.ini")
+        );
+        assert_eq!(
+            sanitize_filename("/abs/path.bin").as_deref(),
+            Some("path.bin")
+        );
+        assert_eq!(sanitize_filename("dir/.."), None);
+        assert_eq!(sanitize_filename("dir/."), None);
+        assert_eq!(sanitize_filename("dir/"), None);
+    }
+
+    #[test]
+    fn sanitize_replaces_and_trims() {
+        assert_eq!(
+            sanitize_filename("a<b>:c|d?*.txt").as_deref(),
+            Some("a_b__c_d__.txt")
+        );
+        assert_eq!(
+            sanitize_filename("tab\there\n").as_deref(),
+            Some("tab_here_")
+        );
+        assert_eq!(
+            sanitize_filename("  name.txt. . ").as_deref(),
+            Some("name.txt")
+        );
+        assert_eq!(sanitize_filename(" . "), None);
+    }
+
+    #[test]
+    fn sanitize_reserved_windows_names() {
+        assert_eq!(sanitize_filename("CON").as_deref(), Some("_CON"));
+        assert_eq!(sanitize_filename("nul.txt").as_deref(), Some("_nul.txt"));
+        assert_eq!(
+            sanitize_filename("com1.tar.gz").as_deref(),
+            Some("_com1.tar.gz")
+        );
+        assert_eq!(sanitize_filename("LPT9").as_deref(), Some("_LPT9"));
+        assert_eq!(sanitize_filename("COM0").as_deref(), Some("COM0"));
+        assert_eq!(
+            sanitize_filename("console.log").as_deref(),
+            Some("console.log")
+        );
+        // a multibyte char straddling the 4th byte
+        assert_eq!(sanitize_filename("abé").as_deref(), Some("abé"));
+    }
+
+    #[test]
+    fn sanitize_truncates_at_char_boundaries() {
+        // 'é' is 2 bytes, so byte 255 falls in the middle of one
+        let name = "é".repeat(200);
+        let sanitized = sanitize_filename(&name).unwrap();
+
+        assert_eq!(sanitized.len(), 254);
+        assert_eq!(sanitized, "é".repeat(127));
+
+        // truncating can't leave a trailing dot or space behind
+        let name = format!("{}. .tail", "x".repeat(252));
+        let sanitized = sanitize_filename(&name).unwrap();
+
+        assert_eq!(sanitized, "x".repeat(252));
+
+        let name = ".".repeat(300);
+        assert_eq!(sanitize_filename(&name), None);
+    }
+
+    #[test]
+    fn infer_prefers_content_disposition_over_url() {
+        let metadata = SourceMetadata {
+            content_disposition: Some("attachment; filename=\"../report.pdf\"".to_owned()),
+            url: Some("https://example.com/download?id=1".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(infer_filename(&metadata).as_deref(), Some("report.pdf"));
+
+        let metadata = SourceMetadata {
+            url: Some("https://example.com/files/my%20archive.tar.gz?sig=abc#frag".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            infer_filename(&metadata).as_deref(),
+            Some("my archive.tar.gz")
+        );
+
+        let metadata = SourceMetadata {
+            url: Some("https://example.com/".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(infer_filename(&metadata), None);
+    }
+}
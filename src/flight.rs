@@ -0,0 +1,335 @@
+//! In-process deduplication of concurrent downloads.
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::io;
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll};
+
+use futures::channel::oneshot;
+use futures::future::{BoxFuture, Shared};
+use futures::FutureExt;
+
+use crate::progress::{ProgressHandle, ProgressHandleShared};
+
+/// The result of a flight, shared between every task awaiting it.
+pub type FlightResult = Result<u64, Arc<io::Error>>;
+
+/// Progress for a flight. Pass this to [`DlFileBuilder::with_progress`] inside the closure
+/// given to [`SingleFlight::download`] so that every task awaiting the flight can follow along.
+///
+/// [`DlFileBuilder::with_progress`]: crate::DlFileBuilder::with_progress
+pub type FlightProgress = Arc<ProgressHandle>;
+
+type FlightMap<K> = Mutex<HashMap<K, Flight>>;
+
+/// Tells flights apart, so a finished flight never removes a newer one for the same key.
+static NEXT_FLIGHT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A registry that ensures only a single download runs at a time for a given key (a path or
+/// URL, typically), with every other caller awaiting the result of the running download.
+///
+/// Cloning a [`SingleFlight`] gives another handle to the same registry.
+pub struct SingleFlight<K = PathBuf> {
+    flights: Arc<FlightMap<K>>,
+}
+
+impl<K> Clone for SingleFlight<K> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            flights: Arc::clone(&self.flights),
+        }
+    }
+}
+
+impl<K> Default for SingleFlight<K> {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            flights: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<K> SingleFlight<K>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+{
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the in-flight download for `key`, or starts a new one by calling `download`.
+    ///
+    /// `download` is only called if nothing is in flight for `key`, and is called after the
+    /// registry is unlocked, so it can take its time, or call back into this
+    /// [`SingleFlight`]. The download runs as long as at least one [`Flight`] for it is being
+    /// polled, and is removed from the registry once it completes (or panics), so a later
+    /// call for the same key starts a fresh download.
+    ///
+    /// If `download` panics, the panic is propagated, and every [`Flight`] already waiting on
+    /// the download resolves to an error.
+    pub fn download<F, Fut>(&self, key: K, download: F) -> Flight
+    where
+        F: FnOnce(FlightProgress) -> Fut,
+        Fut: Future<Output = io::Result<u64>> + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel::<BoxFuture<'static, io::Result<u64>>>();
+        let progress: FlightProgress = Arc::new(ProgressHandle::without_callback());
+
+        let flight = {
+            let mut flights = self.flights.lock().unwrap_or_else(PoisonError::into_inner);
+
+            if let Some(flight) = flights.get(&key) {
+                return flight.follower();
+            }
+
+            let id = NEXT_FLIGHT_ID.fetch_add(1, Relaxed);
+            let deregister = Deregister {
+                registry: Arc::clone(&self.flights),
+                key: key.clone(),
+                id,
+            };
+
+            let result: BoxFuture<'static, FlightResult> = Box::pin(async move {
+                let Ok(fut) = receiver.await else {
+                    return Err(Arc::new(io::Error::other(
+                        "the download for this flight panicked",
+                    )));
+                };
+
+                let result = AssertUnwindSafe(fut).catch_unwind().await;
+
+                // the flight is removed before any panic is propagated, since this future
+                // isn't dropped while the registry still holds it.
+                drop(deregister);
+
+                match result {
+                    Ok(result) => result.map_err(Arc::new),
+                    Err(panic) => std::panic::resume_unwind(panic),
+                }
+            });
+
+            let flight = Flight {
+                result: result.shared(),
+                progress: Arc::clone(progress.shared()),
+                leader: true,
+                id,
+            };
+
+            flights.insert(key.clone(), flight.follower());
+            flight
+        };
+
+        let fut = match std::panic::catch_unwind(AssertUnwindSafe(|| download(progress))) {
+            Ok(fut) => fut,
+            Err(panic) => {
+                // the flight is never going to run, so it's failed (by dropping the sender)
+                // and removed here, instead of waiting for it to be polled.
+                drop(sender);
+                drop(Deregister {
+                    registry: Arc::clone(&self.flights),
+                    key,
+                    id: flight.id,
+                });
+
+                std::panic::resume_unwind(panic)
+            }
+        };
+
+        let _ = sender.send(Box::pin(fut));
+
+        flight
+    }
+
+    /// Returns the in-flight download for `key`, if there is one.
+    pub fn get(&self, key: &K) -> Option<Flight> {
+        self.flights
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(key)
+            .map(Flight::follower)
+    }
+
+    /// Returns the number of downloads currently in flight.
+    pub fn len(&self) -> usize {
+        self.flights
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A handle to an in-flight download, which resolves to the shared result of it.
+///
+/// Cloning a [`Flight`] gives another handle that follows the same download, which is never
+/// the leader.
+pub struct Flight {
+    result: Shared<BoxFuture<'static, FlightResult>>,
+    progress: Arc<ProgressHandleShared>,
+    leader: bool,
+    id: u64,
+}
+
+impl Clone for Flight {
+    #[inline]
+    fn clone(&self) -> Self {
+        self.follower()
+    }
+}
+
+impl Flight {
+    #[inline]
+    fn follower(&self) -> Self {
+        Self {
+            result: self.result.clone(),
+            progress: Arc::clone(&self.progress),
+            leader: false,
+            id: self.id,
+        }
+    }
+
+    /// Live progress of the download, shared between every task awaiting it.
+    #[inline]
+    pub fn progress(&self) -> &Arc<ProgressHandleShared> {
+        &self.progress
+    }
+
+    /// Whether this handle started the download, or is waiting on one started by another
+    /// caller.
+    #[inline]
+    pub fn is_leader(&self) -> bool {
+        self.leader
+    }
+}
+
+impl Future for Flight {
+    type Output = FlightResult;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().result.poll_unpin(cx)
+    }
+}
+
+/// Removes a flight from the registry when dropped, unless it's already been replaced by a
+/// newer flight for the same key.
+struct Deregister<K: Hash + Eq> {
+    registry: Arc<FlightMap<K>>,
+    key: K,
+    id: u64,
+}
+
+impl<K: Hash + Eq> Drop for Deregister<K> {
+    fn drop(&mut self) {
+        let mut flights = self.registry.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if flights
+            .get(&self.key)
+            .is_some_and(|flight| flight.id == self.id)
+        {
+            flights.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::SingleFlight;
+
+    #[tokio::test]
+    async fn concurrent_downloads_join_one_flight() {
+        let flights = SingleFlight::<&str>::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+
+        let leader = flights.download("key", |_| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            async move {
+                released.await.unwrap();
+                Ok(42)
+            }
+        });
+
+        let follower = flights.download("key", |_| async {
+            unreachable!("a flight is already running");
+        });
+
+        assert!(leader.is_leader());
+        assert!(!follower.is_leader());
+        assert!(!leader.clone().is_leader());
+        assert_eq!(flights.len(), 1);
+
+        release.send(()).unwrap();
+        let (leader, follower) = futures::join!(leader, follower);
+
+        assert_eq!(leader.unwrap(), 42);
+        assert_eq!(follower.unwrap(), 42);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert!(flights.is_empty());
+    }
+
+    #[tokio::test]
+    async fn failing_the_leader_fails_every_follower() {
+        let flights = SingleFlight::<&str>::new();
+
+        let leader = flights.download("key", |_| async {
+            Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset"))
+        });
+        let follower = flights.get(&"key").unwrap();
+
+        let (leader, follower) = futures::join!(leader, follower);
+        let (leader, follower) = (leader.unwrap_err(), follower.unwrap_err());
+
+        assert!(Arc::ptr_eq(&leader, &follower));
+        assert_eq!(leader.kind(), io::ErrorKind::ConnectionReset);
+        assert!(flights.is_empty());
+
+        // the failed flight is gone, so the next download starts over
+        assert_eq!(
+            flights.download("key", |_| async { Ok(1) }).await.unwrap(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn panicking_downloads_are_deregistered() {
+        let flights = SingleFlight::<&str>::new();
+
+        let leader = flights.download("future", |_| async { panic!("in the future") });
+        assert!(tokio::spawn(leader).await.unwrap_err().is_panic());
+        assert!(flights.is_empty());
+
+        let closure = std::panic::catch_unwind(|| {
+            flights.download("closure", |_| -> futures::future::Ready<io::Result<u64>> {
+                panic!("in the closure")
+            })
+        });
+        assert!(closure.is_err());
+        assert!(flights.is_empty());
+
+        assert_eq!(
+            flights
+                .download("future", |_| async { Ok(2) })
+                .await
+                .unwrap(),
+            2
+        );
+    }
+}
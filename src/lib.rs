@@ -1,33 +1,86 @@
 use std::mem::ManuallyDrop;
 use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
-use std::pin::Pin;
 use std::sync::Arc;
-use std::task::{Context, Poll};
 use std::{fmt, io};
 
 use bytes::Buf;
 use futures::{Stream, TryStreamExt};
-use reqwest::StatusCode;
 use tokio::fs::File;
-use tokio::io::{AsyncSeekExt, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncSeekExt, AsyncWriteExt};
 use tokio::sync::Semaphore;
 
 mod builder;
+mod cache;
+mod copy;
 mod driver;
+mod encoding;
+mod error;
+#[cfg(feature = "reqwest")]
+mod fetch;
+mod lock;
+#[cfg(feature = "reqwest")]
+mod mirror;
+mod open;
+mod pause;
+mod permit;
+#[cfg(unix)]
+mod perms;
+mod ranges;
+mod reader;
+#[cfg(feature = "reqwest")]
+mod retry;
+mod sink;
+mod spawn;
+mod status;
+mod sync;
+mod tee;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
 mod writer;
 
-pub use writer::DlFileWriter;
+pub use writer::{DlFileWriter, WriterReport};
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod filename;
+pub mod flight;
 pub mod progress;
+pub mod source;
 pub use builder::DlFileBuilder;
+pub use copy::file_url_to_path;
+pub use error::{DlError, DlErrorKind, PartialFile};
+pub use lock::{lock_path, LockBehavior};
+#[cfg(feature = "reqwest")]
+pub use mirror::MirrorDownload;
+pub use pause::PauseHandle;
+pub use permit::PermitWeight;
+#[cfg(feature = "reqwest")]
+pub use retry::RetryPolicy;
+pub use sink::{BytesWriter, DlSink};
+pub use source::{DlSource, SourceMetadata};
+pub use spawn::{DownloadHandle, SpawnedDownload};
+pub use tee::{Tee, TeeDownload, TeeFailure};
 
 pub struct DlFile<P: AsRef<Path> = PathBuf> {
     path: P,
     semaphore: Option<Arc<Semaphore>>,
+    permit_weight: PermitWeight,
+    pause: Option<PauseHandle>,
     delete: Delete,
+    durability: Durability,
+    page_cache: PageCache,
+    status_policy: status::StatusPolicy,
+    max_bytes: Option<u64>,
+    write_buffer: usize,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    io_uring: bool,
+    #[cfg(feature = "reqwest")]
+    retry_policy: RetryPolicy,
     progress: Option<Box<dyn progress::DlProgress>>,
     on_drop_error: fn(&Path, DropError),
     file: ManuallyDrop<File>,
+    // dropped after `file`, and after the `Drop` impl deletes the file (if needed).
+    lock: Option<std::fs::File>,
 }
 
 #[derive(Debug, Default, Clone, Copy)]
@@ -51,6 +104,41 @@ impl Delete {
     }
 }
 
+/// How durable a finished download is, before it gets reported as finished.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Only flush the file. The data may still be sitting in the OS page cache, and can be lost
+    /// if the machine loses power.
+    #[default]
+    Flush,
+    /// Call `sync_all` on the file, then fsync its parent directory so the directory entry
+    /// is durable too.
+    Sync,
+}
+
+/// What happens to the OS page cache as a download is written. Set with
+/// [`DlFileBuilder::page_cache`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PageCache {
+    /// Leave it to the OS, which keeps the written data cached (evicting the cache of other
+    /// files to make room for it).
+    #[default]
+    Keep,
+    /// Write the download back to disk in windows as it's written, and drop each window from
+    /// the page cache once it's on disk, so very large downloads don't evict the cache of
+    /// everything else on the machine.
+    ///
+    /// This also limits how far the download gets ahead of the disk, so it's throttled to the
+    /// speed of the disk instead of filling memory with dirty pages. Uses `sync_file_range`
+    /// and `posix_fadvise(POSIX_FADV_DONTNEED)`, which only exist on Linux. Elsewhere, this is
+    /// the same as [`PageCache::Keep`].
+    ///
+    /// Only data written sequentially from where the download starts is dropped as it goes.
+    /// With a [`DlFileWriter`] that writes out of order, anything past the first gap is only
+    /// written back (and dropped) once the writer is shut down.
+    Drop,
+}
+
 pub enum DropError {
     Metadata(io::Error),
     Deleting(io::Error),
@@ -77,7 +165,14 @@ impl<P: AsRef<Path>> fmt::Debug for DlFile<P> {
         f.debug_struct("DlFile")
             .field("path", &self.path.as_ref().display())
             .field("delete", &self.delete)
+            .field("durability", &self.durability)
+            .field("page_cache", &self.page_cache)
+            .field("status_policy", &self.status_policy)
+            .field("max_bytes", &self.max_bytes)
+            .field("write_buffer", &self.write_buffer)
             .field("semaphore", &self.semaphore)
+            .field("permit_weight", &self.permit_weight)
+            .field("pause", &self.pause)
             .field(
                 "progress",
                 match self.progress.as_ref() {
@@ -86,6 +181,7 @@ impl<P: AsRef<Path>> fmt::Debug for DlFile<P> {
                 },
             )
             .field("file", &*self.file)
+            .field("locked", &self.lock.is_some())
             .finish()
     }
 }
@@ -137,6 +233,13 @@ impl<P: AsRef<Path>> DlFile<P> {
         DlFileBuilder::new(path)
     }
 
+    /// The path the file is being downloaded to. For files opened with
+    /// [`DlFileBuilder::open_in_dir`], this includes the inferred file name.
+    #[inline]
+    pub fn path(&self) -> &Path {
+        self.path.as_ref()
+    }
+
     /// If downloading encountered an error, this can be used to reset the file without
     /// having to close + reopen it.
     ///
@@ -147,6 +250,99 @@ impl<P: AsRef<Path>> DlFile<P> {
         self.file.set_len(0).await
     }
 
+    /// Permits from the semaphore (if there is one) for a download of `size` bytes, according
+    /// to the [`PermitWeight`]. They aren't acquired yet.
+    #[inline]
+    fn permit_slot(&self, size: Option<u64>) -> permit::PermitSlot {
+        permit::PermitSlot::new(self.semaphore.clone(), self.permit_weight.permits(size))
+    }
+
+    /// Acquires permits for a download of `size` bytes, for downloads that don't go through
+    /// the [`driver::DownloadDriver`].
+    async fn acquire_permit(&mut self, size: Option<u64>) -> io::Result<permit::PermitSlot> {
+        let mut permit = self.permit_slot(size);
+        permit.acquire().await?;
+        Ok(permit)
+    }
+
+    /// Waits while the download is paused (see [`PauseHandle::poll_paused`]), then acquires
+    /// the permits again, for downloads that don't go through the [`driver::DownloadDriver`].
+    #[cfg(target_os = "linux")]
+    async fn wait_while_paused(
+        &mut self,
+        was_paused: &mut bool,
+        permit: &mut permit::PermitSlot,
+    ) -> io::Result<()> {
+        let Some(pause) = self.pause.clone() else {
+            return Ok(());
+        };
+
+        let path = self.path.as_ref();
+        let mut progress = self.progress.as_deref_mut();
+
+        futures::future::poll_fn(|cx| {
+            pause.poll_paused(cx, was_paused, permit, progress.as_deref_mut(), path)
+        })
+        .await;
+
+        permit.acquire().await
+    }
+
+    /// What the [`Delete`] policy will do with the file in its current state, when dropped.
+    ///
+    /// Takes `&mut self` (like the other async helpers here) so the future is `Send`, since
+    /// [`DlFile`] isn't `Sync`.
+    async fn partial_file(&mut self) -> PartialFile {
+        match self.delete {
+            Delete::Yes => PartialFile::Deleted,
+            Delete::No => PartialFile::Kept,
+            Delete::IfEmptyOnDrop => match self.file.metadata().await {
+                Ok(meta) if meta.len() == 0 => PartialFile::Deleted,
+                _ => PartialFile::Kept,
+            },
+        }
+    }
+
+    async fn download_error(
+        &mut self,
+        kind: impl Into<DlErrorKind>,
+        bytes_written: u64,
+    ) -> DlError {
+        let kind = kind.into();
+
+        // a download that's too large is never useful, so make sure the default policy
+        // doesn't keep it around.
+        if let (DlErrorKind::TooLarge { .. }, Delete::IfEmptyOnDrop) = (&kind, self.delete) {
+            self.delete = Delete::Yes;
+        }
+
+        DlError::new(
+            kind,
+            self.path.as_ref().to_path_buf(),
+            bytes_written,
+            self.partial_file().await,
+        )
+    }
+
+    /// The async equivalent of the end of [`driver::DownloadDriver`], for downloads that don't
+    /// go through it. Flushes (and syncs, depending on the [`Durability`]) the file, then
+    /// reports the download as finished.
+    async fn finish_download(&mut self) -> io::Result<()> {
+        self.file.flush().await?;
+
+        if self.durability == Durability::Sync {
+            sync::sync_file_and_parent(&self.file, self.path.as_ref())?.await?;
+        }
+
+        if let Some(ref mut prog) = self.progress {
+            prog.finished(self.path.as_ref());
+        }
+
+        Ok(())
+    }
+
+    /// The writer starts at the current position of the file, so anything written through the
+    /// file needs to be flushed first.
     #[inline]
     pub fn into_async_writer(self, estimated_size: Option<u64>) -> DlFileWriter<P> {
         DlFileWriter::new(self, estimated_size)
@@ -161,58 +357,139 @@ impl<P: AsRef<Path>> DlFile<P> {
         &mut self,
         size: Option<u64>,
         stream: S,
-    ) -> io::Result<u64>
+    ) -> Result<u64, DlError>
     where
         S: Stream<Item = io::Result<B>>,
         B: Buf,
     {
+        self.download_from_io_stream_at(0, size, stream).await
+    }
+
+    /// Downloads a stream that resumes a download at `offset` bytes into the file. Progress
+    /// and the size limit include the `offset` bytes, but the returned number of bytes
+    /// (including in errors) only counts what was written from `stream`.
+    async fn download_from_io_stream_at<S, B>(
+        &mut self,
+        offset: u64,
+        size: Option<u64>,
+        stream: S,
+    ) -> Result<u64, DlError>
+    where
+        S: Stream<Item = io::Result<B>>,
+        B: Buf,
+    {
+        if let (Some(limit), Some(size)) = (self.max_bytes, size) {
+            if offset + size > limit {
+                let kind = DlErrorKind::TooLarge {
+                    limit,
+                    content_length: Some(offset + size),
+                };
+
+                return Err(self.download_error(kind, 0).await);
+            }
+        }
+
         futures::pin_mut!(stream);
 
-        let download = driver::DownloadDriver::new(self, stream, size).await;
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if self.io_uring {
+            if let Some((result, bytes_copied)) =
+                uring::download(self, stream.as_mut(), size, offset).await
+            {
+                return match result {
+                    Ok(bytes_copied) => Ok(bytes_copied),
+                    Err(error) => Err(self.download_error(error, bytes_copied).await),
+                };
+            }
+        }
+
+        let (result, bytes_copied) = {
+            let download = match driver::DownloadDriver::new(self, stream, size, offset).await {
+                Ok(download) => download,
+                Err(error) => return Err(self.download_error(error, 0).await),
+            };
+
+            futures::pin_mut!(download);
 
-        futures::pin_mut!(download);
+            let result = download.as_mut().await;
+            (result, download.bytes_copied())
+        };
 
-        download.await
+        match result {
+            Ok(bytes_copied) => Ok(bytes_copied),
+            Err(error) => Err(self.download_error(error, bytes_copied).await),
+        }
     }
 
+    /// Downloads everything from an [`AsyncRead`] source (a socket, child process stdout,
+    /// decompressor, another file, etc), until it hits EOF.
     #[inline]
-    pub async fn download_from_response(&mut self, response: reqwest::Response) -> io::Result<u64> {
-        #[inline]
-        fn reqwest_error_to_io_error(error: reqwest::Error) -> io::Error {
-            let kind = if let Some(status) = error.status() {
-                match status {
-                    StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
-                        io::ErrorKind::PermissionDenied
-                    }
-                    StatusCode::CONFLICT => io::ErrorKind::AlreadyExists,
-                    StatusCode::NOT_FOUND | StatusCode::GONE => io::ErrorKind::NotFound,
-                    _ if (400..500).contains(&status.as_u16()) => io::ErrorKind::InvalidInput,
-                    _ if (500..600).contains(&status.as_u16()) => io::ErrorKind::ConnectionAborted,
-                    _ => io::ErrorKind::Other,
-                }
-            } else if error.is_timeout() {
-                io::ErrorKind::TimedOut
-            } else if error.is_connect() {
-                io::ErrorKind::ConnectionAborted
-            } else if error.is_decode() || error.is_body() {
-                io::ErrorKind::InvalidData
-            } else if error.is_request() || error.is_builder() {
-                io::ErrorKind::InvalidInput
-            } else if error.is_redirect() {
-                io::ErrorKind::ConnectionReset
-            } else {
-                io::ErrorKind::Other
-            };
+    pub async fn download_from_reader<R>(
+        &mut self,
+        size: Option<u64>,
+        reader: R,
+    ) -> Result<u64, DlError>
+    where
+        R: AsyncRead,
+    {
+        let stream = reader::ReaderStream::new(reader, reader::DEFAULT_READ_CAPACITY);
+        self.download_from_io_stream(size, stream).await
+    }
 
-            io::Error::new(kind, error)
+    /// Downloads everything from a [`DlSource`], using its metadata for the expected size.
+    ///
+    /// For HTTP sources, the response status is checked before anything is written, and
+    /// statuses that aren't accepted (by default, anything other than `2xx`) are returned as
+    /// a [`DlErrorKind::Status`] error. See [`DlFileBuilder::accept_statuses`].
+    #[inline]
+    pub async fn download_from_source<S>(&mut self, source: S) -> Result<u64, DlError>
+    where
+        S: DlSource,
+    {
+        self.download_from_source_at(0, source).await
+    }
+
+    /// Checks the status of a source against the status policy, before anything is written.
+    /// A rejected status is returned without a body snippet.
+    async fn check_status(&mut self, metadata: &SourceMetadata) -> Result<(), DlErrorKind> {
+        if metadata.status.is_none() {
+            return Ok(());
         }
 
-        self.download_from_stream(
-            response.content_length(),
-            response.bytes_stream(),
-            reqwest_error_to_io_error,
-        )
-        .await
+        let position = self.file.stream_position().await?;
+        self.status_policy.check(metadata, position)
+    }
+
+    /// [`download_from_source`], resuming at `offset`. See [`download_from_io_stream_at`].
+    ///
+    /// [`download_from_source`]: Self::download_from_source
+    /// [`download_from_io_stream_at`]: Self::download_from_io_stream_at
+    async fn download_from_source_at<S>(&mut self, offset: u64, source: S) -> Result<u64, DlError>
+    where
+        S: DlSource,
+    {
+        let metadata = source.metadata();
+
+        if let Err(mut kind) = self.check_status(&metadata).await {
+            if let DlErrorKind::Status { ref mut body, .. } = kind {
+                let max = self.status_policy.error_body_snippet;
+                *body = status::read_body_snippet(source.into_stream(), max).await;
+            }
+
+            return Err(self.download_error(kind, 0).await);
+        }
+
+        self.download_from_io_stream_at(offset, metadata.content_length, source.into_stream())
+            .await
+    }
+
+    #[cfg(feature = "reqwest")]
+    #[inline]
+    pub async fn download_from_response(
+        &mut self,
+        response: reqwest::Response,
+    ) -> Result<u64, DlError> {
+        self.download_from_source(response).await
     }
 
     #[inline]
@@ -221,7 +498,7 @@ impl<P: AsRef<Path>> DlFile<P> {
         size: Option<u64>,
         stream: S,
         map_err: F,
-    ) -> io::Result<u64>
+    ) -> Result<u64, DlError>
     where
         S: Stream<Item = Result<B, E>>,
         B: Buf,
@@ -0,0 +1,182 @@
+use std::ffi::OsString;
+use std::fs::TryLockError;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// What to do when another process already holds the lock for a download path.
+///
+/// Locks are advisory, and are taken on a `<file name>.lock` file next to the download
+/// target (rather than the target itself), so that the target isn't created or truncated
+/// before the lock is held. The lock is held until the [`DlFile`] is dropped, after any
+/// deletion from its [`Delete`] policy happens.
+///
+/// The `.lock` file itself is left behind once the lock is released. Deleting it would race
+/// with other processes that already opened it and are waiting on the lock, which would then
+/// hold a lock on a file nobody else can see, while a new process creates (and locks) a fresh
+/// one. Stale `.lock` files are empty, and safe to delete when nothing is downloading.
+///
+/// [`DlFile`]: crate::DlFile
+/// [`Delete`]: crate::Delete
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockBehavior {
+    /// Wait for the other process to release the lock. Once acquired, the
+    /// [`OverwriteBehavior`] is checked against whatever the other process left behind, so
+    /// with [`OverwriteBehavior::Dont`] or [`OverwriteBehavior::DoIfEmpty`], a finished
+    /// download from the other process results in an [`io::ErrorKind::AlreadyExists`] error
+    /// that can be used to reuse the existing file.
+    ///
+    /// [`OverwriteBehavior`]: crate::OverwriteBehavior
+    /// [`OverwriteBehavior::Dont`]: crate::OverwriteBehavior::Dont
+    /// [`OverwriteBehavior::DoIfEmpty`]: crate::OverwriteBehavior::DoIfEmpty
+    ///
+    /// Async [`DlFile`]s poll the lock with a backoff (of up to half a second), rather than
+    /// blocking a thread on it, so cancelling the open (i.e. with [`tokio::time::timeout`])
+    /// stops waiting, and the lock is never taken afterwards.
+    ///
+    /// [`DlFile`]: crate::DlFile
+    Wait,
+    /// Return an [`io::ErrorKind::WouldBlock`] error immediately.
+    FailFast,
+}
+
+/// Returns the path to the lock file used for `path`.
+pub fn lock_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().map(OsString::from).unwrap_or_default();
+
+    file_name.push(".lock");
+    path.with_file_name(file_name)
+}
+
+/// The first and longest waits between attempts to take the lock with [`LockBehavior::Wait`].
+const MIN_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_BACKOFF: Duration = Duration::from_millis(500);
+
+pub(crate) async fn acquire(path: &Path, behavior: LockBehavior) -> io::Result<std::fs::File> {
+    let lock_path = lock_path(path);
+
+    let (lock_file, lock_path) = tokio::task::spawn_blocking(move || {
+        let lock_file = open(&lock_path)?;
+        io::Result::Ok((lock_file, lock_path))
+    })
+    .await
+    .map_err(io::Error::other)??;
+
+    let mut backoff = MIN_BACKOFF;
+
+    // the lock file is closed if this is cancelled, so a lock can't be taken after that.
+    while !try_lock(&lock_file)? {
+        if behavior == LockBehavior::FailFast {
+            return Err(locked_error(&lock_path));
+        }
+
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+
+    Ok(lock_file)
+}
+
+/// [`acquire`], blocking the current thread while waiting for the lock.
+#[cfg(feature = "blocking")]
+pub(crate) fn acquire_blocking(path: &Path, behavior: LockBehavior) -> io::Result<std::fs::File> {
+    let lock_path = lock_path(path);
+    let lock_file = open(&lock_path)?;
+
+    match behavior {
+        LockBehavior::FailFast => match try_lock(&lock_file)? {
+            true => Ok(lock_file),
+            false => Err(locked_error(&lock_path)),
+        },
+        LockBehavior::Wait => {
+            lock_file.lock()?;
+            Ok(lock_file)
+        }
+    }
+}
+
+fn open(lock_path: &Path) -> io::Result<std::fs::File> {
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(lock_path)
+}
+
+/// Takes the lock without blocking, returning `false` if another process holds it.
+fn try_lock(lock_file: &std::fs::File) -> io::Result<bool> {
+    match lock_file.try_lock() {
+        Ok(()) => Ok(true),
+        Err(TryLockError::WouldBlock) => Ok(false),
+        Err(TryLockError::Error(error)) => Err(error),
+    }
+}
+
+fn locked_error(lock_path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::WouldBlock,
+        format!("'{}' is locked by another process", lock_path.display()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{acquire, lock_path, LockBehavior};
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("dl-file-lock-{name}-{}", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn fails_fast_while_locked() {
+        let path = temp_path("fail-fast");
+        let held = acquire(&path, LockBehavior::FailFast).await.unwrap();
+
+        let error = acquire(&path, LockBehavior::FailFast).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::WouldBlock);
+
+        drop(held);
+        let lock = acquire(&path, LockBehavior::FailFast).await.unwrap();
+        drop(lock);
+
+        std::fs::remove_file(lock_path(&path)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn waits_until_unlocked() {
+        let path = temp_path("wait");
+        let held = acquire(&path, LockBehavior::Wait).await.unwrap();
+
+        let release = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(held);
+        };
+
+        let (lock, ()) = tokio::join!(acquire(&path, LockBehavior::Wait), release);
+        drop(lock.unwrap());
+
+        std::fs::remove_file(lock_path(&path)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelled_waits_never_take_the_lock() {
+        let path = temp_path("cancel");
+        let held = acquire(&path, LockBehavior::FailFast).await.unwrap();
+
+        let wait = tokio::time::timeout(
+            Duration::from_millis(20),
+            acquire(&path, LockBehavior::Wait),
+        );
+        assert!(wait.await.is_err());
+
+        drop(held);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let lock = acquire(&path, LockBehavior::FailFast).await.unwrap();
+        drop(lock);
+
+        std::fs::remove_file(lock_path(&path)).unwrap();
+    }
+}
@@ -0,0 +1,343 @@
+use std::path::Path;
+
+use bytes::Bytes;
+use futures::{StreamExt, TryStreamExt};
+use http::StatusCode;
+use reqwest::{Client, IntoUrl, Url};
+
+use crate::fetch::FetchState;
+use crate::status::StatusPolicy;
+use crate::{DlError, DlErrorKind, DlFile, DlSource};
+
+/// The result of a successful [`DlFile::download_from_mirrors`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MirrorDownload {
+    /// The total number of bytes in the file.
+    pub bytes: u64,
+    /// The index of the mirror that finished the download.
+    pub mirror: usize,
+    /// The URL of the mirror that finished the download.
+    pub url: Url,
+}
+
+impl<P: AsRef<Path>> DlFile<P> {
+    /// Downloads from an ordered list of mirrors of the same file, moving on to the next mirror
+    /// when one fails with a retryable error (see [`DlError::is_retryable`]), or the file
+    /// isn't found on it (`404` or `410`).
+    ///
+    /// When moving to the next mirror, the download resumes from the bytes already written
+    /// with a range request, as long as the next mirror has a matching validator (`ETag` or
+    /// `Last-Modified`). Otherwise, the file is reset and downloaded from the start.
+    ///
+    /// The file is reset before the first mirror is tried. Any other error is returned
+    /// immediately. If every mirror fails, the error from the last mirror is returned.
+    pub async fn download_from_mirrors<U>(
+        &mut self,
+        client: &Client,
+        mirrors: impl IntoIterator<Item = U>,
+    ) -> Result<MirrorDownload, DlError>
+    where
+        U: IntoUrl,
+    {
+        let mut state = FetchState::default();
+        let mut last_error = None;
+
+        if let Err(error) = self.reset().await {
+            return Err(self.download_error(error, 0).await);
+        }
+
+        for (mirror, url) in mirrors.into_iter().enumerate() {
+            let url = match url.into_url() {
+                Ok(url) => url,
+                Err(error) => {
+                    let error = self
+                        .download_error(DlErrorKind::Reqwest(error), state.offset)
+                        .await;
+                    return Err(error);
+                }
+            };
+
+            match self.fetch_into(client.get(url.clone()), &mut state).await {
+                Ok(bytes) => return Ok(MirrorDownload { bytes, mirror, url }),
+                Err(error) if should_failover(&error) => last_error = Some(error),
+                Err(error) => return Err(error),
+            }
+        }
+
+        match last_error {
+            Some(error) => Err(error),
+            None => {
+                let error =
+                    std::io::Error::new(std::io::ErrorKind::InvalidInput, "no mirrors given");
+                Err(self.download_error(error, 0).await)
+            }
+        }
+    }
+
+    /// Races the same request against every mirror, keeping whichever mirror is the first
+    /// to send `probe_bytes` bytes (or its whole response, if smaller), and cancelling the
+    /// rest.
+    ///
+    /// Only the winning response is written into the file, starting with the bytes it sent
+    /// while racing, and progress is only reported for the winner. This cuts tail latency
+    /// when a mirror is degraded, unlike [`download_from_mirrors`], which only moves on to
+    /// the next mirror after a failure. If the winner fails after it's chosen, the error is
+    /// returned (and [`download_from_mirrors`] can be used to retry).
+    ///
+    /// Mirrors that fail, or respond with a status that isn't accepted, drop out of the race.
+    /// If every mirror drops out, the last error is returned.
+    ///
+    /// With a semaphore (see [`DlFileBuilder::with_semaphore`]), the race waits for a single
+    /// permit before any request is sent, and holds it until the winner is chosen. The winner
+    /// then waits for its own permits (see [`PermitWeight`]), like any other download.
+    ///
+    /// [`DlFileBuilder::with_semaphore`]: crate::DlFileBuilder::with_semaphore
+    /// [`PermitWeight`]: crate::PermitWeight
+    /// [`download_from_mirrors`]: Self::download_from_mirrors
+    pub async fn download_from_fastest_mirror<U>(
+        &mut self,
+        client: &Client,
+        mirrors: impl IntoIterator<Item = U>,
+        probe_bytes: usize,
+    ) -> Result<MirrorDownload, DlError>
+    where
+        U: IntoUrl,
+    {
+        if let Err(error) = self.reset().await {
+            return Err(self.download_error(error, 0).await);
+        }
+
+        let mut urls = Vec::new();
+
+        for url in mirrors {
+            match url.into_url() {
+                Ok(url) => urls.push(url),
+                Err(error) => {
+                    return Err(self.download_error(DlErrorKind::Reqwest(error), 0).await)
+                }
+            }
+        }
+
+        if urls.is_empty() {
+            let error = std::io::Error::new(std::io::ErrorKind::InvalidInput, "no mirrors given");
+            return Err(self.download_error(error, 0).await);
+        }
+
+        // the size isn't known until a mirror responds, so the race counts as a single permit.
+        let race_permit = match self.acquire_permit(None).await {
+            Ok(permit) => permit,
+            Err(error) => return Err(self.download_error(error, 0).await),
+        };
+
+        let status_policy = self.status_policy.clone();
+
+        let races = urls
+            .into_iter()
+            .enumerate()
+            .map(|(mirror, url)| Box::pin(probe(client, &status_policy, mirror, url, probe_bytes)));
+
+        // dropping the losing futures cancels their requests.
+        let winner = match futures::future::select_ok(races).await {
+            Ok((winner, _losers)) => winner,
+            Err(kind) => return Err(self.download_error(kind, 0).await),
+        };
+
+        // released before the download acquires its own, so a semaphore with exactly
+        // `max_permits` permits can't deadlock.
+        drop(race_permit);
+
+        let stream = futures::stream::iter(winner.buffered.into_iter().map(Ok)).chain(winner.rest);
+
+        let bytes = self
+            .download_from_io_stream_at(0, winner.content_length, stream)
+            .await?;
+
+        Ok(MirrorDownload {
+            bytes,
+            mirror: winner.mirror,
+            url: winner.url,
+        })
+    }
+}
+
+/// A mirror that got through the race, with the bytes it sent while racing.
+struct Probe {
+    mirror: usize,
+    url: Url,
+    content_length: Option<u64>,
+    buffered: Vec<Bytes>,
+    rest: <reqwest::Response as DlSource>::Stream,
+}
+
+async fn probe(
+    client: &Client,
+    status_policy: &StatusPolicy,
+    mirror: usize,
+    url: Url,
+    probe_bytes: usize,
+) -> Result<Probe, DlErrorKind> {
+    let response = client
+        .get(url.clone())
+        .send()
+        .await
+        .map_err(DlErrorKind::Reqwest)?;
+
+    let metadata = response.metadata();
+    status_policy.check(&metadata, 0)?;
+
+    let mut rest = response.into_stream();
+    let mut buffered = Vec::new();
+    let mut read = 0;
+
+    while read < probe_bytes {
+        match rest.try_next().await? {
+            Some(chunk) => {
+                read += chunk.len();
+                buffered.push(chunk);
+            }
+            None => break,
+        }
+    }
+
+    Ok(Probe {
+        mirror,
+        url,
+        content_length: metadata.content_length,
+        buffered,
+        rest,
+    })
+}
+
+#[inline]
+pub(crate) fn should_failover(error: &DlError) -> bool {
+    error.is_retryable()
+        || matches!(
+            error.status(),
+            Some(StatusCode::NOT_FOUND | StatusCode::GONE)
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    use tokio::sync::Semaphore;
+
+    use crate::DlFile;
+
+    /// Serves `response` to every connection, sending back each request received.
+    fn serve(response: &'static str) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file", listener.local_addr().unwrap());
+        let (sender, requests) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..read]);
+                }
+
+                let _ = sender.send(String::from_utf8(request).unwrap().to_lowercase());
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    const NOT_FOUND: &str =
+        "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+    const FOUND: &str = "HTTP/1.1 200 OK\r\ncontent-length: 5\r\nconnection: close\r\n\r\nhello";
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("dl-file-mirror-{name}-{}", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_the_next_mirror() {
+        let (missing, _) = serve(NOT_FOUND);
+        let (found, _) = serve(FOUND);
+
+        let path = temp_path("failover");
+        let mut file = DlFile::builder(&path).open_overwrite().await.unwrap();
+
+        let download = file
+            .download_from_mirrors(&reqwest::Client::new(), [&missing, &found])
+            .await
+            .unwrap();
+
+        let contents = std::fs::read(&path).unwrap();
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(download.bytes, 5);
+        assert_eq!(download.mirror, 1);
+        assert_eq!(contents, b"hello");
+    }
+
+    #[tokio::test]
+    async fn fastest_mirror_skips_mirrors_that_fail() {
+        let (missing, _) = serve(NOT_FOUND);
+        let (found, _) = serve(FOUND);
+
+        let path = temp_path("fastest");
+        let mut file = DlFile::builder(&path).open_overwrite().await.unwrap();
+
+        let download = file
+            .download_from_fastest_mirror(&reqwest::Client::new(), [&missing, &found], 2)
+            .await
+            .unwrap();
+
+        let contents = std::fs::read(&path).unwrap();
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(download.bytes, 5);
+        assert_eq!(download.mirror, 1);
+        assert_eq!(contents, b"hello");
+    }
+
+    #[tokio::test]
+    async fn fastest_mirror_waits_for_a_permit_before_probing() {
+        let (first, first_requests) = serve(FOUND);
+        let (second, second_requests) = serve(FOUND);
+
+        let semaphore = Arc::new(Semaphore::new(1));
+        let held = Arc::clone(&semaphore).acquire_owned().await.unwrap();
+
+        let path = temp_path("permit");
+        let mut file = DlFile::builder(&path)
+            .with_semaphore_ref(&semaphore)
+            .open_overwrite()
+            .await
+            .unwrap();
+
+        let client = reqwest::Client::new();
+        let download = file.download_from_fastest_mirror(&client, [&first, &second], 5);
+
+        let release = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let probed = first_requests.try_recv().is_ok() || second_requests.try_recv().is_ok();
+            drop(held);
+            probed
+        };
+
+        let (download, probed_while_waiting) = tokio::join!(download, release);
+        let contents = std::fs::read(&path).unwrap();
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(!probed_while_waiting);
+        assert_eq!(download.unwrap().bytes, 5);
+        assert_eq!(contents, b"hello");
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+}
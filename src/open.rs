@@ -0,0 +1,257 @@
+//! Creating the download file (and its parent directories), shared by the async and blocking
+//! builders.
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{Durability, OverwriteBehavior};
+
+/// The parent directories created by [`Opener::create_parent_dirs`], from the outermost one
+/// down, which still need [`Opener::finish_dirs`].
+#[derive(Debug, Default)]
+#[must_use]
+pub(crate) struct CreatedDirs(Vec<PathBuf>);
+
+/// How a download file and its missing parent directories are created.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Opener {
+    pub(crate) durability: Durability,
+    #[cfg(unix)]
+    pub(crate) perms: crate::perms::UnixPerms,
+}
+
+impl Opener {
+    /// Creates any missing parent directories of `path`, with the configured permissions.
+    /// With [`Durability::Sync`], they're fsynced too.
+    ///
+    /// The exact directory mode + owner is only applied by [`finish_dirs`], so the
+    /// directories stay accessible until the file (and its lock file) are created in them.
+    ///
+    /// [`finish_dirs`]: Self::finish_dirs
+    pub(crate) async fn create_parent_dirs(self, path: &Path) -> io::Result<CreatedDirs> {
+        let path = path.to_path_buf();
+
+        tokio::task::spawn_blocking(move || self.create_parent_dirs_blocking(&path))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// The blocking equivalent of [`create_parent_dirs`].
+    ///
+    /// [`create_parent_dirs`]: Self::create_parent_dirs
+    pub(crate) fn create_parent_dirs_blocking(&self, path: &Path) -> io::Result<CreatedDirs> {
+        let Some(parent) = path.parent() else {
+            return Ok(CreatedDirs::default());
+        };
+
+        let mut missing = Vec::new();
+
+        for dir in parent.ancestors() {
+            // relative paths end with an empty ancestor, which is the current directory
+            if dir.as_os_str().is_empty() || dir.try_exists()? {
+                break;
+            }
+
+            missing.push(dir);
+        }
+
+        let Some(&outermost) = missing.last() else {
+            return Ok(CreatedDirs::default());
+        };
+
+        let mut created = CreatedDirs::default();
+
+        for dir in missing.into_iter().rev() {
+            match self.create_dir(dir) {
+                Ok(()) => created.0.push(dir.to_path_buf()),
+                // another process created it in the meantime, so it isn't ours to change.
+                Err(error) if error.kind() == io::ErrorKind::AlreadyExists && dir.is_dir() => {}
+                Err(error) => {
+                    let _ = self.finish_dirs_blocking(created);
+                    return Err(error);
+                }
+            }
+        }
+
+        if self.durability == Durability::Sync {
+            let existing_ancestor = outermost.parent().unwrap_or(Path::new(""));
+            crate::sync::sync_dir_chain_blocking(existing_ancestor, parent)?;
+        }
+
+        Ok(created)
+    }
+
+    fn create_dir(&self, dir: &Path) -> io::Result<()> {
+        #[cfg(unix)]
+        return self.perms.create_dir(dir);
+        #[cfg(not(unix))]
+        return std::fs::create_dir(dir);
+    }
+
+    /// Applies the exact directory mode + owner to the directories created by
+    /// [`create_parent_dirs`], once nothing else needs to be created in them.
+    ///
+    /// [`create_parent_dirs`]: Self::create_parent_dirs
+    pub(crate) async fn finish_dirs(self, created: CreatedDirs) -> io::Result<()> {
+        if created.0.is_empty() {
+            return Ok(());
+        }
+
+        tokio::task::spawn_blocking(move || self.finish_dirs_blocking(created))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// The blocking equivalent of [`finish_dirs`].
+    ///
+    /// [`finish_dirs`]: Self::finish_dirs
+    pub(crate) fn finish_dirs_blocking(&self, created: CreatedDirs) -> io::Result<()> {
+        #[cfg(unix)]
+        self.perms.apply_to_created_dirs(&created.0)?;
+        #[cfg(not(unix))]
+        drop(created);
+
+        Ok(())
+    }
+
+    /// Opens (or creates) the file at `path` for writing, according to `overwrite_behavior`,
+    /// with the configured permissions.
+    pub(crate) async fn open(
+        self,
+        path: &Path,
+        overwrite_behavior: OverwriteBehavior,
+    ) -> io::Result<tokio::fs::File> {
+        let path = path.to_path_buf();
+
+        tokio::task::spawn_blocking(move || self.open_blocking(&path, overwrite_behavior))
+            .await
+            .map_err(io::Error::other)?
+            .map(tokio::fs::File::from_std)
+    }
+
+    /// The blocking equivalent of [`open`].
+    ///
+    /// [`open`]: Self::open
+    pub(crate) fn open_blocking(
+        &self,
+        path: &Path,
+        overwrite_behavior: OverwriteBehavior,
+    ) -> io::Result<File> {
+        let file = match overwrite_behavior {
+            OverwriteBehavior::Do => self.options(false).open(path)?,
+            OverwriteBehavior::Dont => self.options(true).open(path)?,
+            OverwriteBehavior::DoIfEmpty => match std::fs::metadata(path) {
+                Ok(meta) if meta.len() == 0 => self.options(false).open(path)?,
+                Ok(meta) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!(
+                            "non-empty ({} bytes) file '{}' already exists",
+                            meta.len(),
+                            path.display()
+                        ),
+                    ));
+                }
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                    self.options(false).open(path)?
+                }
+                Err(error) => return Err(error),
+            },
+        };
+
+        #[cfg(unix)]
+        self.perms.apply_to_file(&file)?;
+
+        Ok(file)
+    }
+
+    /// The equivalent of [`File::create`] (or [`File::create_new`] if `new` is set), with the
+    /// configured permissions applied.
+    fn options(&self, new: bool) -> std::fs::OpenOptions {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true);
+
+        match new {
+            true => options.create_new(true),
+            false => options.create(true).truncate(true),
+        };
+
+        #[cfg(unix)]
+        self.perms.apply_to_open_options(&mut options);
+
+        options
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    use super::Opener;
+    use crate::perms::UnixPerms;
+    use crate::{Durability, OverwriteBehavior};
+
+    fn mode(path: &Path) -> u32 {
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn dir_modes_only_apply_to_created_dirs_once_the_file_is_open() {
+        let root = std::env::temp_dir().join(format!("dl-file-open-dirs-{}", std::process::id()));
+        let (existing, outer) = (root.join("existing"), root.join("existing/outer"));
+        let (inner, path) = (outer.join("inner"), outer.join("inner/file"));
+
+        std::fs::create_dir_all(&existing).unwrap();
+        std::fs::set_permissions(&existing, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let opener = Opener {
+            durability: Durability::Sync,
+            perms: UnixPerms {
+                dir_mode: Some(0o600),
+                ..UnixPerms::default()
+            },
+        };
+
+        let created = opener.create_parent_dirs_blocking(&path).unwrap();
+
+        // still traversable, so the file can be created
+        assert_eq!(mode(&outer), 0o700);
+        assert_eq!(mode(&inner), 0o700);
+
+        let file = opener
+            .open_blocking(&path, OverwriteBehavior::Dont)
+            .unwrap();
+        opener.finish_dirs_blocking(created).unwrap();
+        drop(file);
+
+        let modes = [mode(&existing), mode(&outer), mode(&inner)];
+
+        std::fs::set_permissions(&outer, std::fs::Permissions::from_mode(0o700)).unwrap();
+        std::fs::set_permissions(&inner, std::fs::Permissions::from_mode(0o700)).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(modes, [0o755, 0o600, 0o600]);
+    }
+
+    #[test]
+    fn existing_parents_create_nothing() {
+        let path = std::env::temp_dir().join("dl-file-open-existing");
+
+        let opener = Opener {
+            durability: Durability::Flush,
+            perms: UnixPerms {
+                dir_mode: Some(0o700),
+                ..UnixPerms::default()
+            },
+        };
+
+        let created = opener.create_parent_dirs_blocking(&path).unwrap();
+        assert!(created.0.is_empty());
+
+        let created = opener
+            .create_parent_dirs_blocking(Path::new("relative"))
+            .unwrap();
+        assert!(created.0.is_empty());
+    }
+}
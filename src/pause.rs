@@ -0,0 +1,310 @@
+use std::path::Path;
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::Ordering::{Acquire, Release};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll, Waker};
+
+use crate::permit::PermitSlot;
+use crate::progress::DlProgress;
+
+const RUNNING: u8 = 0;
+const PAUSED: u8 = 1;
+const PAUSED_RELEASING_PERMIT: u8 = 2;
+
+/// Pauses and resumes a download, from anywhere. Set with [`DlFileBuilder::pause_handle`].
+///
+/// While paused, the download stops polling its source (so the connection stays open, and
+/// the server is slowed down by backpressure) and stops writing to the file, but keeps all the
+/// progress made so far. Progress reporters get [`DlProgress::paused`] and
+/// [`DlProgress::resumed`] calls.
+///
+/// If the server drops the connection during a long pause, [`DlFile::download_with`] retries
+/// (if its [`RetryPolicy`] allows it) with a fresh range request once resumed.
+///
+/// A handle can control any number of downloads at once (i.e. to pause everything), and every
+/// download and [`resumed`] call waiting on it is woken when it's resumed. Cloning gives
+/// another handle to the same downloads.
+///
+/// [`DlFileBuilder::pause_handle`]: crate::DlFileBuilder::pause_handle
+/// [`DlProgress::paused`]: crate::progress::DlProgress::paused
+/// [`DlProgress::resumed`]: crate::progress::DlProgress::resumed
+/// [`DlFile::download_with`]: crate::DlFile::download_with
+/// [`RetryPolicy`]: crate::RetryPolicy
+/// [`resumed`]: Self::resumed
+#[derive(Debug, Clone, Default)]
+pub struct PauseHandle {
+    shared: Arc<PauseShared>,
+}
+
+#[derive(Debug, Default)]
+struct PauseShared {
+    state: AtomicU8,
+    // every task waiting to be resumed, since a single waker slot would only wake the last one.
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl PauseHandle {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pauses the download, keeping its semaphore permits.
+    #[inline]
+    pub fn pause(&self) {
+        self.set_state(PAUSED);
+    }
+
+    /// Pauses the download, and gives its semaphore permits back, so other downloads can run
+    /// in the meantime. The permits are acquired again when resumed.
+    #[inline]
+    pub fn pause_releasing_permit(&self) {
+        self.set_state(PAUSED_RELEASING_PERMIT);
+    }
+
+    #[inline]
+    pub fn resume(&self) {
+        self.set_state(RUNNING);
+    }
+
+    #[inline]
+    pub fn is_paused(&self) -> bool {
+        self.shared.state.load(Acquire) != RUNNING
+    }
+
+    #[inline]
+    fn set_state(&self, state: u8) {
+        self.shared.state.store(state, Release);
+
+        let wakers = std::mem::take(&mut *self.wakers());
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    #[inline]
+    fn wakers(&self) -> std::sync::MutexGuard<'_, Vec<Waker>> {
+        self.shared
+            .wakers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Waits until the download is resumed, or returns immediately if it isn't paused.
+    pub async fn resumed(&self) {
+        futures::future::poll_fn(|cx| self.poll_resumed(cx)).await
+    }
+
+    fn poll_resumed(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.is_paused() {
+            return Poll::Ready(());
+        }
+
+        {
+            let mut wakers = self.wakers();
+
+            if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+        }
+
+        // check again, in case it was resumed before the waker was registered.
+        match self.is_paused() {
+            true => Poll::Pending,
+            false => Poll::Ready(()),
+        }
+    }
+
+    /// Returns `Pending` while paused, keeping `was_paused` and the progress up to date, and
+    /// releasing the permits if asked to. Once resumed, the caller needs to acquire the
+    /// permits again.
+    pub(crate) fn poll_paused<P: DlProgress + ?Sized>(
+        &self,
+        cx: &mut Context<'_>,
+        was_paused: &mut bool,
+        permit: &mut PermitSlot,
+        progress: Option<&mut P>,
+        path: &Path,
+    ) -> Poll<()> {
+        let state = self.shared.state.load(Acquire);
+
+        if state == RUNNING && !*was_paused {
+            return Poll::Ready(());
+        }
+
+        if state == PAUSED_RELEASING_PERMIT {
+            permit.release();
+        }
+
+        match self.poll_resumed(cx) {
+            Poll::Ready(_) => {
+                if std::mem::take(was_paused) {
+                    if let Some(prog) = progress {
+                        prog.resumed(path);
+                    }
+                }
+
+                Poll::Ready(())
+            }
+            Poll::Pending => {
+                if !std::mem::replace(was_paused, true) {
+                    if let Some(prog) = progress {
+                        prog.paused(path);
+                    }
+                }
+
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use futures::channel::mpsc;
+    use futures::SinkExt;
+    use tokio::sync::Semaphore;
+
+    use super::PauseHandle;
+    use crate::progress::ProgressContainer;
+    use crate::DlSink;
+
+    /// Waits (for up to a second) until `condition` holds.
+    async fn wait_until(condition: impl Fn() -> bool) {
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while !condition() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn resuming_wakes_every_waiter() {
+        let pause = PauseHandle::new();
+        pause.pause();
+        assert!(pause.is_paused());
+
+        // on separate tasks, so they each have their own waker
+        let waiters: Vec<_> = (0..2)
+            .map(|_| {
+                let pause = pause.clone();
+                tokio::spawn(async move { pause.resumed().await })
+            })
+            .collect();
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        pause.resume();
+
+        for waiter in waiters {
+            let resumed = tokio::time::timeout(Duration::from_secs(1), waiter).await;
+            assert!(resumed.is_ok());
+        }
+
+        assert!(!pause.is_paused());
+    }
+
+    #[tokio::test]
+    async fn paused_downloads_stop_and_report_it() {
+        let pause = PauseHandle::new();
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        struct Events(Arc<Mutex<Vec<&'static str>>>);
+
+        impl crate::progress::DlProgress for Events {
+            fn start(&mut self, _: &Path, _: Option<u64>) {}
+
+            fn update(&mut self, _: &Path, _: u64) {
+                self.0.lock().unwrap().push("update");
+            }
+
+            fn finished(&mut self, _: &Path) {
+                self.0.lock().unwrap().push("finished");
+            }
+
+            fn paused(&mut self, _: &Path) {
+                self.0.lock().unwrap().push("paused");
+            }
+
+            fn resumed(&mut self, _: &Path) {
+                self.0.lock().unwrap().push("resumed");
+            }
+        }
+
+        let mut sink = DlSink::in_memory()
+            .pause_handle(pause.clone())
+            .with_progress(Events(Arc::clone(&events)))
+            .write_buffer(0);
+
+        pause.pause();
+
+        let chunks = futures::stream::iter([Ok(Bytes::from_static(b"hello"))]);
+        let download = sink.download_from_io_stream(Some(5), chunks);
+
+        let resume = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let while_paused = events.lock().unwrap().clone();
+            pause.resume();
+            while_paused
+        };
+
+        let (result, while_paused) = tokio::join!(download, resume);
+
+        assert_eq!(result.unwrap(), 5);
+        assert_eq!(while_paused, ["paused"]);
+        assert_eq!(
+            *events.lock().unwrap(),
+            ["paused", "resumed", "update", "finished"]
+        );
+        assert_eq!(sink.into_inner(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn pausing_can_release_the_permit() {
+        let pause = PauseHandle::new();
+        let semaphore = Arc::new(Semaphore::new(1));
+        let written = Arc::new(AtomicU64::new(0));
+
+        let progress = ProgressContainer::new(
+            Arc::clone(&written),
+            |_: &mut Arc<AtomicU64>, _: &Path, _| {},
+            |written: &mut Arc<AtomicU64>, _: &Path, bytes| written.store(bytes, Ordering::Relaxed),
+            |_: &mut Arc<AtomicU64>, _: &Path| {},
+        );
+
+        let mut sink = DlSink::in_memory()
+            .pause_handle(pause.clone())
+            .with_semaphore_ref(&semaphore)
+            .with_progress(progress)
+            .write_buffer(0);
+
+        let (mut sender, chunks) = mpsc::channel::<std::io::Result<Bytes>>(1);
+        let download = sink.download_from_io_stream(None, chunks);
+
+        let control = async {
+            sender.send(Ok(Bytes::from_static(b"hello"))).await.unwrap();
+            wait_until(|| written.load(Ordering::Relaxed) == 5).await;
+            assert_eq!(semaphore.available_permits(), 0);
+
+            // the download notices the pause once its next chunk arrives
+            pause.pause_releasing_permit();
+            sender.send(Ok(Bytes::from_static(b"world"))).await.unwrap();
+            wait_until(|| semaphore.available_permits() == 1).await;
+            assert_eq!(written.load(Ordering::Relaxed), 5);
+
+            pause.resume();
+            sender.close_channel();
+        };
+
+        let (result, ()) = tokio::join!(download, control);
+
+        assert_eq!(result.unwrap(), 10);
+        assert_eq!(semaphore.available_permits(), 1);
+        assert_eq!(sink.into_inner(), b"helloworld");
+    }
+}
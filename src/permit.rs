@@ -0,0 +1,186 @@
+use std::io;
+use std::num::NonZeroU64;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use futures::future::BoxFuture;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// How many permits a download takes from the semaphore set with
+/// [`DlFileBuilder::with_semaphore`], so a few huge downloads can count for more than dozens of
+/// small ones.
+///
+/// `max_permits` shouldn't be larger than the number of permits the semaphore was created
+/// with, otherwise large downloads will wait forever.
+///
+/// [`DlFileBuilder::with_semaphore`]: crate::DlFileBuilder::with_semaphore
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PermitWeight {
+    /// Every download takes a single permit.
+    #[default]
+    One,
+    /// Downloads take a permit per `bytes_per_permit` bytes of their expected size (rounded
+    /// up), between 1 and `max_permits`. Downloads with an unknown size take a single permit.
+    PerBytes {
+        bytes_per_permit: NonZeroU64,
+        max_permits: u32,
+    },
+}
+
+impl PermitWeight {
+    /// A permit per `mib` MiB of expected size, up to `max_permits`.
+    #[inline]
+    pub const fn per_mib(mib: NonZeroU64, max_permits: u32) -> Self {
+        Self::PerBytes {
+            bytes_per_permit: mib.saturating_mul(NonZeroU64::new(1024 * 1024).unwrap()),
+            max_permits,
+        }
+    }
+
+    /// The number of permits a download of `size` bytes takes.
+    pub fn permits(&self, size: Option<u64>) -> u32 {
+        match (*self, size) {
+            (Self::One, _) | (Self::PerBytes { .. }, None) => 1,
+            (
+                Self::PerBytes {
+                    bytes_per_permit,
+                    max_permits,
+                },
+                Some(size),
+            ) => {
+                let permits = size.div_ceil(bytes_per_permit.get());
+                u32::try_from(permits)
+                    .unwrap_or(u32::MAX)
+                    .clamp(1, max_permits.max(1))
+            }
+        }
+    }
+}
+
+/// Acquires `permits` permits, returning an error instead of panicking if the semaphore was
+/// closed.
+pub(crate) async fn acquire(
+    semaphore: Arc<Semaphore>,
+    permits: u32,
+) -> io::Result<OwnedSemaphorePermit> {
+    semaphore
+        .acquire_many_owned(permits)
+        .await
+        .map_err(|_| io::Error::other("the download semaphore was closed"))
+}
+
+/// Permits for a download, which can be released (i.e. while paused) and acquired again from
+/// a `poll` method.
+pub(crate) struct PermitSlot {
+    semaphore: Option<Arc<Semaphore>>,
+    permits: u32,
+    permit: Option<OwnedSemaphorePermit>,
+    acquire: Option<BoxFuture<'static, io::Result<OwnedSemaphorePermit>>>,
+}
+
+impl PermitSlot {
+    #[inline]
+    pub(crate) fn new(semaphore: Option<Arc<Semaphore>>, permits: u32) -> Self {
+        Self {
+            semaphore,
+            permits,
+            permit: None,
+            acquire: None,
+        }
+    }
+
+    /// Waits for the permits, if there's a semaphore and they aren't already held.
+    pub(crate) fn poll_acquire(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.permit.is_some() {
+            return Poll::Ready(Ok(()));
+        }
+
+        let Some(ref semaphore) = self.semaphore else {
+            return Poll::Ready(Ok(()));
+        };
+
+        let acquire = match self.acquire {
+            Some(ref mut acquire) => acquire,
+            None => self
+                .acquire
+                .insert(Box::pin(acquire(Arc::clone(semaphore), self.permits))),
+        };
+
+        let result = ready!(acquire.as_mut().poll(cx));
+        self.acquire = None;
+        self.permit = Some(result?);
+
+        Poll::Ready(Ok(()))
+    }
+
+    #[inline]
+    pub(crate) async fn acquire(&mut self) -> io::Result<()> {
+        futures::future::poll_fn(|cx| self.poll_acquire(cx)).await
+    }
+
+    /// Gives the permits back to the semaphore, until they're acquired again.
+    #[inline]
+    pub(crate) fn release(&mut self) {
+        self.permit = None;
+        self.acquire = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU64;
+    use std::sync::Arc;
+
+    use tokio::sync::Semaphore;
+
+    use super::{PermitSlot, PermitWeight};
+
+    #[test]
+    fn per_bytes_permits_are_clamped() {
+        let weight = PermitWeight::PerBytes {
+            bytes_per_permit: NonZeroU64::new(100).unwrap(),
+            max_permits: 4,
+        };
+
+        assert_eq!(weight.permits(None), 1);
+        assert_eq!(weight.permits(Some(0)), 1);
+        assert_eq!(weight.permits(Some(100)), 1);
+        assert_eq!(weight.permits(Some(101)), 2);
+        assert_eq!(weight.permits(Some(1000)), 4);
+        assert_eq!(weight.permits(Some(u64::MAX)), 4);
+
+        // a `max_permits` of 0 still takes a permit
+        let weight = PermitWeight::PerBytes {
+            bytes_per_permit: NonZeroU64::new(1).unwrap(),
+            max_permits: 0,
+        };
+        assert_eq!(weight.permits(Some(1000)), 1);
+
+        assert_eq!(PermitWeight::One.permits(Some(u64::MAX)), 1);
+        assert_eq!(
+            PermitWeight::per_mib(NonZeroU64::new(2).unwrap(), 8).permits(Some(5 << 20)),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn acquires_releases_and_fails_on_closed_semaphores() {
+        let semaphore = Arc::new(Semaphore::new(3));
+        let mut slot = PermitSlot::new(Some(Arc::clone(&semaphore)), 2);
+
+        slot.acquire().await.unwrap();
+        // already held, so this doesn't take any more
+        slot.acquire().await.unwrap();
+        assert_eq!(semaphore.available_permits(), 1);
+
+        slot.release();
+        assert_eq!(semaphore.available_permits(), 3);
+
+        semaphore.close();
+        let error = slot.acquire().await.unwrap_err();
+        assert_eq!(error.to_string(), "the download semaphore was closed");
+
+        // without a semaphore, there's nothing to wait for
+        PermitSlot::new(None, 2).acquire().await.unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+use std::fs::{DirBuilder, File, OpenOptions, Permissions};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Unix permissions + ownership to apply to the files and directories created by
+/// [`DlFileBuilder::open`] (and its blocking equivalent).
+///
+/// [`DlFileBuilder::open`]: crate::DlFileBuilder::open
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct UnixPerms {
+    pub(crate) file_mode: Option<u32>,
+    pub(crate) dir_mode: Option<u32>,
+    pub(crate) uid: Option<u32>,
+    pub(crate) gid: Option<u32>,
+}
+
+impl UnixPerms {
+    #[inline]
+    fn has_owner(&self) -> bool {
+        self.uid.is_some() || self.gid.is_some()
+    }
+
+    /// Sets the creation mode, so the file never exists with a more permissive mode than
+    /// requested.
+    #[inline]
+    pub(crate) fn apply_to_open_options(&self, options: &mut OpenOptions) {
+        if let Some(mode) = self.file_mode {
+            options.mode(mode);
+        }
+    }
+
+    /// Creates `dir` (but not its parents). With a directory mode, it's created with the
+    /// owner bits forced on, so whatever goes inside can still be created until
+    /// [`apply_to_created_dirs`] sets the exact mode.
+    ///
+    /// [`apply_to_created_dirs`]: Self::apply_to_created_dirs
+    pub(crate) fn create_dir(&self, dir: &Path) -> io::Result<()> {
+        let mut builder = DirBuilder::new();
+
+        if let Some(mode) = self.dir_mode {
+            builder.mode(mode | 0o700);
+        }
+
+        builder.create(dir)
+    }
+
+    /// The mode passed at creation time is masked by the umask, so after opening, the exact
+    /// mode + owner is set on the open file descriptor. This can add back bits the umask
+    /// removed, but since a new file was created with the requested mode (minus the umask),
+    /// it's never more accessible than requested at any point. Existing files that are opened
+    /// keep their old mode until this runs.
+    pub(crate) fn apply_to_file(&self, file: &File) -> io::Result<()> {
+        if self.has_owner() {
+            std::os::unix::fs::fchown(file, self.uid, self.gid)?;
+        }
+
+        if let Some(mode) = self.file_mode {
+            file.set_permissions(Permissions::from_mode(mode))?;
+        }
+
+        Ok(())
+    }
+
+    /// Applies the exact directory mode + owner to directories created with [`create_dir`],
+    /// which are listed from the outermost one down. They're changed from the innermost one
+    /// up, so a mode without search permission doesn't lock the rest out.
+    ///
+    /// [`create_dir`]: Self::create_dir
+    pub(crate) fn apply_to_created_dirs(&self, created: &[PathBuf]) -> io::Result<()> {
+        if self.dir_mode.is_none() && !self.has_owner() {
+            return Ok(());
+        }
+
+        for dir in created.iter().rev() {
+            if self.has_owner() {
+                std::os::unix::fs::chown(dir, self.uid, self.gid)?;
+            }
+
+            if let Some(mode) = self.dir_mode {
+                std::fs::set_permissions(dir, Permissions::from_mode(mode))?;
+            }
+        }
+
+        Ok(())
+    }
+}
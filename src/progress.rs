@@ -9,6 +9,20 @@ pub trait DlProgress: Send {
     fn update(&mut self, path: &Path, bytes_written: u64);
 
     fn finished(&mut self, path: &Path);
+
+    /// Called when a download is paused with a [`PauseHandle`].
+    ///
+    /// [`PauseHandle`]: crate::PauseHandle
+    #[inline]
+    fn paused(&mut self, path: &Path) {
+        let _ = path;
+    }
+
+    /// Called when a paused download is resumed.
+    #[inline]
+    fn resumed(&mut self, path: &Path) {
+        let _ = path;
+    }
 }
 
 impl<P: DlProgress + ?Sized> DlProgress for &mut P {
@@ -26,6 +40,16 @@ impl<P: DlProgress + ?Sized> DlProgress for &mut P {
     fn finished(&mut self, path: &Path) {
         P::finished(self, path)
     }
+
+    #[inline]
+    fn paused(&mut self, path: &Path) {
+        P::paused(self, path)
+    }
+
+    #[inline]
+    fn resumed(&mut self, path: &Path) {
+        P::resumed(self, path)
+    }
 }
 
 impl<P: DlProgress + ?Sized> DlProgress for Box<P> {
@@ -43,6 +67,16 @@ impl<P: DlProgress + ?Sized> DlProgress for Box<P> {
     fn finished(&mut self, path: &Path) {
         P::finished(self, path)
     }
+
+    #[inline]
+    fn paused(&mut self, path: &Path) {
+        P::paused(self, path)
+    }
+
+    #[inline]
+    fn resumed(&mut self, path: &Path) {
+        P::resumed(self, path)
+    }
 }
 
 impl<P> DlProgress for Arc<P>
@@ -64,6 +98,16 @@ where
     fn finished(&mut self, path: &Path) {
         <&P as DlProgress>::finished(&mut &**self, path)
     }
+
+    #[inline]
+    fn paused(&mut self, path: &Path) {
+        <&P as DlProgress>::paused(&mut &**self, path)
+    }
+
+    #[inline]
+    fn resumed(&mut self, path: &Path) {
+        <&P as DlProgress>::resumed(&mut &**self, path)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -156,6 +200,7 @@ pub struct ProgressHandleShared {
     total_bytes: AtomicU64,
     bytes_written: AtomicU64,
     finished: AtomicBool,
+    paused: AtomicBool,
 }
 
 impl<F> ProgressHandle<F> {
@@ -166,6 +211,7 @@ impl<F> ProgressHandle<F> {
                 total_bytes: AtomicU64::new(0),
                 bytes_written: AtomicU64::new(0),
                 finished: AtomicBool::new(false),
+                paused: AtomicBool::new(false),
             }),
             on_update,
         }
@@ -177,11 +223,24 @@ impl<F> ProgressHandle<F> {
     }
 }
 
+impl ProgressHandle {
+    /// A handle that only tracks progress in its [`ProgressHandleShared`], for handing out
+    /// live progress of downloads the crate drives itself.
+    #[inline]
+    pub(crate) fn without_callback() -> Self {
+        Self::new(noop_update)
+    }
+}
+
+fn noop_update(_: &Path, _: u64, _: Option<u64>, _: DlState) {}
+
 impl ProgressHandleShared {
     #[inline]
     pub fn state(&self) -> DlState {
         if self.is_finished() {
             DlState::Finished
+        } else if self.is_paused() {
+            DlState::Paused
         } else if self.get_bytes_written() == 0 {
             DlState::Starting
         } else {
@@ -194,6 +253,11 @@ impl ProgressHandleShared {
         self.finished.load(Relaxed)
     }
 
+    #[inline]
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Relaxed)
+    }
+
     #[inline]
     pub fn get_bytes_written(&self) -> u64 {
         self.bytes_written.load(Relaxed)
@@ -212,6 +276,7 @@ impl ProgressHandleShared {
 pub enum DlState {
     Starting,
     Running,
+    Paused,
     Finished,
 }
 
@@ -250,4 +315,28 @@ where
             DlState::Finished,
         );
     }
+
+    #[inline]
+    fn paused(&mut self, path: &Path) {
+        self.shared.paused.store(true, Relaxed);
+
+        (self.on_update)(
+            path,
+            self.shared.get_bytes_written(),
+            self.shared.get_total_bytes(),
+            DlState::Paused,
+        );
+    }
+
+    #[inline]
+    fn resumed(&mut self, path: &Path) {
+        self.shared.paused.store(false, Relaxed);
+
+        (self.on_update)(
+            path,
+            self.shared.get_bytes_written(),
+            self.shared.get_total_bytes(),
+            DlState::Running,
+        );
+    }
 }
@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+
+/// A set of byte ranges, for tracking which parts of a file have been written when they can
+/// be written out of order.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct RangeSet {
+    // start -> end, without any overlapping or adjacent ranges.
+    ranges: BTreeMap<u64, u64>,
+    len: u64,
+}
+
+impl RangeSet {
+    /// Adds `start..end`, returning the number of bytes that weren't already in the set.
+    pub(crate) fn insert(&mut self, mut start: u64, mut end: u64) -> u64 {
+        if start >= end {
+            return 0;
+        }
+
+        let before = self.len;
+
+        // a range that starts before `start` and reaches it is merged in
+        if let Some((&prev_start, &prev_end)) = self.ranges.range(..start).next_back() {
+            if prev_end >= start {
+                start = prev_start;
+                end = end.max(prev_end);
+                self.remove(prev_start, prev_end);
+            }
+        }
+
+        // as is every range that starts inside (or right after) the new one
+        while let Some((&next_start, &next_end)) = self.ranges.range(start..=end).next() {
+            end = end.max(next_end);
+            self.remove(next_start, next_end);
+        }
+
+        self.ranges.insert(start, end);
+        self.len += end - start;
+
+        self.len - before
+    }
+
+    #[inline]
+    fn remove(&mut self, start: u64, end: u64) {
+        self.ranges.remove(&start);
+        self.len -= end - start;
+    }
+
+    /// The total number of bytes in the set.
+    #[inline]
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// The end of the range that covers `pos`, or `pos` itself if nothing does.
+    pub(crate) fn contiguous_end(&self, pos: u64) -> u64 {
+        match self.ranges.range(..=pos).next_back() {
+            Some((_, &end)) if end >= pos => end,
+            _ => pos,
+        }
+    }
+
+    /// The end of the last range, or 0 if the set is empty.
+    #[inline]
+    pub(crate) fn end(&self) -> u64 {
+        self.ranges.values().next_back().copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RangeSet;
+
+    fn ranges(set: &RangeSet) -> Vec<(u64, u64)> {
+        set.ranges
+            .iter()
+            .map(|(&start, &end)| (start, end))
+            .collect()
+    }
+
+    #[test]
+    fn out_of_order_inserts() {
+        let mut set = RangeSet::default();
+
+        assert_eq!(set.insert(20, 30), 10);
+        assert_eq!(set.insert(0, 10), 10);
+        assert_eq!(set.insert(40, 45), 5);
+
+        assert_eq!(ranges(&set), [(0, 10), (20, 30), (40, 45)]);
+        assert_eq!(set.len(), 25);
+        assert_eq!(set.end(), 45);
+    }
+
+    #[test]
+    fn adjacent_inserts_merge() {
+        let mut set = RangeSet::default();
+
+        assert_eq!(set.insert(10, 20), 10);
+        assert_eq!(set.insert(0, 10), 10);
+        assert_eq!(set.insert(20, 25), 5);
+
+        assert_eq!(ranges(&set), [(0, 25)]);
+        assert_eq!(set.len(), 25);
+    }
+
+    #[test]
+    fn contained_rewrites_add_nothing() {
+        let mut set = RangeSet::default();
+        set.insert(0, 100);
+
+        assert_eq!(set.insert(0, 100), 0);
+        assert_eq!(set.insert(10, 20), 0);
+        assert_eq!(set.insert(99, 100), 0);
+        assert_eq!(set.insert(50, 50), 0);
+
+        assert_eq!(ranges(&set), [(0, 100)]);
+        assert_eq!(set.len(), 100);
+    }
+
+    #[test]
+    fn spans_merge_several_ranges() {
+        let mut set = RangeSet::default();
+        set.insert(0, 10);
+        set.insert(20, 30);
+        set.insert(40, 50);
+        set.insert(60, 70);
+
+        // overlaps the first, covers the middle two, and stops right before the last
+        assert_eq!(set.insert(5, 60), 30);
+
+        assert_eq!(ranges(&set), [(0, 70)]);
+        assert_eq!(set.len(), 70);
+        assert_eq!(set.end(), 70);
+    }
+
+    #[test]
+    fn contiguous_end() {
+        let mut set = RangeSet::default();
+        assert_eq!(set.contiguous_end(0), 0);
+
+        set.insert(10, 20);
+        set.insert(30, 40);
+
+        assert_eq!(set.contiguous_end(0), 0);
+        assert_eq!(set.contiguous_end(10), 20);
+        assert_eq!(set.contiguous_end(15), 20);
+        assert_eq!(set.contiguous_end(20), 20);
+        assert_eq!(set.contiguous_end(25), 25);
+
+        set.insert(20, 30);
+        assert_eq!(set.contiguous_end(10), 40);
+    }
+}
@@ -0,0 +1,128 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Bytes, BytesMut};
+use futures::Stream;
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// The default number of bytes read from an [`AsyncRead`] at once.
+pub(crate) const DEFAULT_READ_CAPACITY: usize = 64 * 1024;
+
+pin_project_lite::pin_project! {
+    /// Adapts an [`AsyncRead`] into a stream of [`Bytes`] chunks, so it can be driven by the
+    /// same download machinery as any other stream.
+    pub(crate) struct ReaderStream<R> {
+        #[pin]
+        reader: Option<R>,
+        buf: BytesMut,
+        capacity: usize,
+    }
+}
+
+impl<R: AsyncRead> ReaderStream<R> {
+    #[inline]
+    pub(crate) fn new(reader: R, capacity: usize) -> Self {
+        Self {
+            reader: Some(reader),
+            buf: BytesMut::new(),
+            capacity,
+        }
+    }
+}
+
+impl<R: AsyncRead> Stream for ReaderStream<R> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+
+        let Some(reader) = this.reader.as_mut().as_pin_mut() else {
+            return Poll::Ready(None);
+        };
+
+        this.buf.reserve(*this.capacity);
+
+        // `reserve` can leave more spare capacity than asked for, i.e. when it reclaims the
+        // whole allocation, so only `capacity` bytes of it are read into.
+        let dst = this.buf.spare_capacity_mut();
+        let len = dst.len().min(*this.capacity);
+        let mut read_buf = ReadBuf::uninit(&mut dst[..len]);
+
+        match reader.poll_read(cx, &mut read_buf) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Err(error)) => {
+                this.reader.set(None);
+                Poll::Ready(Some(Err(error)))
+            }
+            Poll::Ready(Ok(())) => {
+                let filled = read_buf.filled().len();
+
+                if filled == 0 {
+                    this.reader.set(None);
+                    return Poll::Ready(None);
+                }
+
+                // SAFETY: `poll_read` initialized the first `filled` bytes of the spare capacity.
+                unsafe { this.buf.set_len(this.buf.len() + filled) };
+
+                Poll::Ready(Some(Ok(this.buf.split().freeze())))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures::StreamExt;
+    use tokio::io::{AsyncRead, ReadBuf};
+
+    use super::ReaderStream;
+
+    /// Reads `data` once, then fails.
+    struct FailAfter(&'static [u8]);
+
+    impl AsyncRead for FailAfter {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if self.0.is_empty() {
+                return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+            }
+
+            buf.put_slice(self.0);
+            self.0 = &[];
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_chunks_of_at_most_the_capacity() {
+        let mut stream = ReaderStream::new(&b"hello world"[..], 4);
+        let mut chunks = Vec::new();
+
+        while let Some(chunk) = stream.next().await {
+            chunks.push(chunk.unwrap());
+        }
+
+        assert_eq!(chunks, ["hell", "o wo", "rld"]);
+        // stays finished after EOF
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn ends_after_an_error() {
+        let mut stream = ReaderStream::new(FailAfter(b"hello"), 64);
+
+        assert_eq!(stream.next().await.unwrap().unwrap(), "hello");
+        let error = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionReset);
+        assert!(stream.next().await.is_none());
+    }
+}
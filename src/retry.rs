@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+/// How (and how often) a download is retried after a retryable error (see
+/// [`DlError::is_retryable`]), by methods that can rebuild the request themselves, like
+/// [`DlFile::download_with`].
+///
+/// Retries resume from the bytes already written when possible.
+///
+/// [`DlError::is_retryable`]: crate::DlError::is_retryable
+/// [`DlFile::download_with`]: crate::DlFile::download_with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    /// The delay before the first retry, which doubles with each retry after that.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Never retry.
+    pub const NONE: Self = Self {
+        max_retries: 0,
+        initial_backoff: Duration::ZERO,
+        max_backoff: Duration::ZERO,
+    };
+
+    /// Retry up to `max_retries` times, starting with a 500ms backoff, up to 30s.
+    #[inline]
+    pub const fn new(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+
+    /// The delay before retry number `retry` (starting at 0).
+    #[inline]
+    pub(crate) fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2_u32.saturating_pow(retry))
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    #[inline]
+    fn default() -> Self {
+        Self::NONE
+    }
+}
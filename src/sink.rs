@@ -0,0 +1,492 @@
+//! Downloading into memory, or any other [`AsyncWrite`], instead of a file.
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, BytesMut};
+use futures::{Stream, TryStreamExt};
+use http::StatusCode;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Semaphore;
+
+use crate::driver::{DownloadDriver, DriverParts};
+use crate::permit::PermitSlot;
+use crate::progress::DlProgress;
+use crate::status::{self, StatusPolicy};
+use crate::{reader, DlError, DlErrorKind, DlSource, PartialFile, PauseHandle, PermitWeight};
+
+/// What a [`DlSink`] is called in progress reports and errors, unless it's given a name.
+const DEFAULT_NAME: &str = "sink";
+
+/// Downloads into any [`AsyncWrite`] (i.e. a `Vec<u8>` or [`BytesWriter`] for small blobs that
+/// don't belong on disk, or a socket), with the same semaphore, pause, progress and size limit behavior as a
+/// [`DlFile`].
+///
+/// There's no path, so progress and errors use the sink's [`name`] instead. Errors always
+/// report the partial data as [`PartialFile::Kept`], since whatever was written stays in the
+/// writer. The writer is flushed once a download finishes, but never shut down.
+///
+/// [`DlFile`]: crate::DlFile
+/// [`name`]: Self::name
+pub struct DlSink<W = Vec<u8>> {
+    writer: W,
+    name: PathBuf,
+    semaphore: Option<Arc<Semaphore>>,
+    permit_weight: PermitWeight,
+    pause: Option<PauseHandle>,
+    status_policy: StatusPolicy,
+    max_bytes: Option<u64>,
+    write_buffer: usize,
+    progress: Option<Box<dyn DlProgress>>,
+}
+
+impl DlSink<Vec<u8>> {
+    /// A sink that downloads into memory. Use [`max_bytes`] to cap how much it can hold.
+    ///
+    /// [`max_bytes`]: Self::max_bytes
+    #[inline]
+    pub fn in_memory() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl DlSink<BytesWriter> {
+    /// A sink that downloads into a [`BytesMut`], which can be frozen into [`Bytes`] without
+    /// copying. Use [`max_bytes`] to cap how much it can hold.
+    ///
+    /// [`Bytes`]: bytes::Bytes
+    /// [`max_bytes`]: Self::max_bytes
+    #[inline]
+    pub fn in_memory_bytes() -> Self {
+        Self::new(BytesWriter::default())
+    }
+}
+
+impl<W> DlSink<W> {
+    #[inline]
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            name: PathBuf::from(DEFAULT_NAME),
+            semaphore: None,
+            permit_weight: PermitWeight::default(),
+            pause: None,
+            status_policy: StatusPolicy::default(),
+            max_bytes: None,
+            write_buffer: crate::driver::DEFAULT_WRITE_BUFFER,
+            progress: None,
+        }
+    }
+
+    /// Sets the name the sink is reported with, in place of a path.
+    #[inline]
+    pub fn name(mut self, name: impl Into<PathBuf>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// See [`DlFileBuilder::accept_statuses`].
+    ///
+    /// [`DlFileBuilder::accept_statuses`]: crate::DlFileBuilder::accept_statuses
+    #[inline]
+    pub fn accept_statuses(mut self, statuses: impl IntoIterator<Item = StatusCode>) -> Self {
+        self.status_policy.accepted = Some(statuses.into_iter().collect());
+        self
+    }
+
+    /// See [`DlFileBuilder::error_body_snippet`].
+    ///
+    /// [`DlFileBuilder::error_body_snippet`]: crate::DlFileBuilder::error_body_snippet
+    #[inline]
+    pub fn error_body_snippet(mut self, max_bytes: usize) -> Self {
+        self.status_policy.error_body_snippet = max_bytes;
+        self
+    }
+
+    /// Limits the size of each download into the sink. See [`DlFileBuilder::max_bytes`].
+    ///
+    /// Unlike a file, the sink doesn't know what its writer already holds, so the limit only
+    /// counts what each download writes. An in-memory sink that's reused for several downloads
+    /// can hold up to `max_bytes` for each of them.
+    ///
+    /// [`DlFileBuilder::max_bytes`]: crate::DlFileBuilder::max_bytes
+    #[inline]
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Sets how many bytes are coalesced from a stream before they're written to the sink.
+    /// See [`DlFileBuilder::write_buffer`].
+    ///
+    /// [`DlFileBuilder::write_buffer`]: crate::DlFileBuilder::write_buffer
+    #[inline]
+    pub fn write_buffer(mut self, capacity: usize) -> Self {
+        self.write_buffer = capacity;
+        self
+    }
+
+    #[inline]
+    pub fn with_semaphore(mut self, semaphore: Arc<Semaphore>) -> Self {
+        self.semaphore = Some(semaphore);
+        self
+    }
+
+    #[inline]
+    pub fn with_semaphore_ref(self, semaphore: &Arc<Semaphore>) -> Self {
+        self.with_semaphore(Arc::clone(semaphore))
+    }
+
+    /// See [`DlFileBuilder::permit_weight`].
+    ///
+    /// [`DlFileBuilder::permit_weight`]: crate::DlFileBuilder::permit_weight
+    #[inline]
+    pub fn permit_weight(mut self, permit_weight: PermitWeight) -> Self {
+        self.permit_weight = permit_weight;
+        self
+    }
+
+    /// Lets downloads into the sink be paused and resumed with `pause`.
+    #[inline]
+    pub fn pause_handle(mut self, pause: PauseHandle) -> Self {
+        self.pause = Some(pause);
+        self
+    }
+
+    #[inline]
+    pub fn with_progress(mut self, progress: impl DlProgress + 'static) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// The name the sink is reported with.
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.name
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    #[inline]
+    fn download_error(&self, kind: impl Into<DlErrorKind>, bytes_written: u64) -> DlError {
+        DlError::new(
+            kind.into(),
+            self.name.clone(),
+            bytes_written,
+            PartialFile::Kept,
+        )
+    }
+}
+
+impl<W: AsyncWrite + Unpin> DlSink<W> {
+    /// Downloads everything from a stream into the sink. See
+    /// [`DlFile::download_from_io_stream`].
+    ///
+    /// [`DlFile::download_from_io_stream`]: crate::DlFile::download_from_io_stream
+    pub async fn download_from_io_stream<S, B>(
+        &mut self,
+        size: Option<u64>,
+        stream: S,
+    ) -> Result<u64, DlError>
+    where
+        S: Stream<Item = io::Result<B>>,
+        B: Buf,
+    {
+        if let (Some(limit), Some(size)) = (self.max_bytes, size) {
+            if size > limit {
+                let kind = DlErrorKind::TooLarge {
+                    limit,
+                    content_length: Some(size),
+                };
+
+                return Err(self.download_error(kind, 0));
+            }
+        }
+
+        futures::pin_mut!(stream);
+
+        let (result, bytes_copied) = {
+            let parts = DriverParts {
+                path: &self.name,
+                permit: PermitSlot::new(self.semaphore.clone(), self.permit_weight.permits(size)),
+                pause: self.pause.clone(),
+                progress: match self.progress {
+                    Some(ref mut prog) => Some(&mut *prog),
+                    None => None,
+                },
+                max_bytes: self.max_bytes,
+                write_buffer: self.write_buffer,
+            };
+
+            let writer = Pin::new(&mut self.writer);
+
+            let download = match DownloadDriver::with_writer(parts, writer, stream, size, 0).await {
+                Ok(download) => download,
+                Err(error) => return Err(self.download_error(error, 0)),
+            };
+
+            futures::pin_mut!(download);
+
+            let result = download.as_mut().await;
+            (result, download.bytes_copied())
+        };
+
+        result.map_err(|error| self.download_error(error, bytes_copied))
+    }
+
+    /// Downloads everything from an [`AsyncRead`] source, until it hits EOF.
+    #[inline]
+    pub async fn download_from_reader<R>(
+        &mut self,
+        size: Option<u64>,
+        reader: R,
+    ) -> Result<u64, DlError>
+    where
+        R: AsyncRead,
+    {
+        let stream = reader::ReaderStream::new(reader, reader::DEFAULT_READ_CAPACITY);
+        self.download_from_io_stream(size, stream).await
+    }
+
+    /// Downloads everything from a [`DlSource`], checking the response status first. See
+    /// [`DlFile::download_from_source`].
+    ///
+    /// [`DlFile::download_from_source`]: crate::DlFile::download_from_source
+    pub async fn download_from_source<S>(&mut self, source: S) -> Result<u64, DlError>
+    where
+        S: DlSource,
+    {
+        let metadata = source.metadata();
+
+        // the sink isn't seekable, so only a response starting from the beginning is accepted.
+        if let Err(mut kind) = self.status_policy.check(&metadata, 0) {
+            if let DlErrorKind::Status { ref mut body, .. } = kind {
+                let max = self.status_policy.error_body_snippet;
+                *body = status::read_body_snippet(source.into_stream(), max).await;
+            }
+
+            return Err(self.download_error(kind, 0));
+        }
+
+        self.download_from_io_stream(metadata.content_length, source.into_stream())
+            .await
+    }
+
+    #[cfg(feature = "reqwest")]
+    #[inline]
+    pub async fn download_from_response(
+        &mut self,
+        response: reqwest::Response,
+    ) -> Result<u64, DlError> {
+        self.download_from_source(response).await
+    }
+
+    #[inline]
+    pub async fn download_from_stream<S, B, E, F>(
+        &mut self,
+        size: Option<u64>,
+        stream: S,
+        map_err: F,
+    ) -> Result<u64, DlError>
+    where
+        S: Stream<Item = Result<B, E>>,
+        B: Buf,
+        F: FnMut(E) -> io::Error,
+    {
+        self.download_from_io_stream(size, stream.map_err(map_err))
+            .await
+    }
+}
+
+/// An [`AsyncWrite`] that appends to a [`BytesMut`], for downloading into memory with a
+/// [`DlSink`]. See [`DlSink::in_memory_bytes`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BytesWriter(BytesMut);
+
+impl BytesWriter {
+    #[inline]
+    pub fn get_ref(&self) -> &BytesMut {
+        &self.0
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut BytesMut {
+        &mut self.0
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> BytesMut {
+        self.0
+    }
+}
+
+impl From<BytesMut> for BytesWriter {
+    #[inline]
+    fn from(bytes: BytesMut) -> Self {
+        Self(bytes)
+    }
+}
+
+impl AsyncWrite for BytesWriter {
+    #[inline]
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().0.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let bytes = &mut self.get_mut().0;
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+
+        bytes.reserve(len);
+        for buf in bufs {
+            bytes.extend_from_slice(buf);
+        }
+
+        Poll::Ready(Ok(len))
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+// the writer isn't included, since an in-memory sink would print everything downloaded.
+impl<W> fmt::Debug for DlSink<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DlSink")
+            .field("name", &self.name)
+            .field("status_policy", &self.status_policy)
+            .field("max_bytes", &self.max_bytes)
+            .field("write_buffer", &self.write_buffer)
+            .field("semaphore", &self.semaphore)
+            .field("permit_weight", &self.permit_weight)
+            .field("pause", &self.pause)
+            .field(
+                "progress",
+                match self.progress.as_ref() {
+                    None => &"None",
+                    Some(_) => &"Some(...)",
+                },
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::stream;
+
+    use super::DlSink;
+    use crate::DlErrorKind;
+
+    fn chunks(chunks: &[&'static [u8]]) -> impl futures::Stream<Item = std::io::Result<Bytes>> {
+        let chunks: Vec<_> = chunks
+            .iter()
+            .map(|chunk| Ok(Bytes::from_static(chunk)))
+            .collect();
+        stream::iter(chunks)
+    }
+
+    #[tokio::test]
+    async fn downloads_into_memory() {
+        let mut sink = DlSink::in_memory();
+        let copied = sink
+            .download_from_io_stream(Some(11), chunks(&[b"hello", b" world"]))
+            .await
+            .unwrap();
+
+        assert_eq!(copied, 11);
+        assert_eq!(sink.into_inner(), b"hello world");
+
+        let mut sink = DlSink::in_memory_bytes();
+        let copied = sink
+            .download_from_io_stream(None, chunks(&[b"hello", b" world"]))
+            .await
+            .unwrap();
+
+        assert_eq!(copied, 11);
+        assert_eq!(sink.into_inner().into_inner().freeze(), "hello world");
+    }
+
+    #[tokio::test]
+    async fn rejects_downloads_over_the_limit() {
+        let mut sink = DlSink::in_memory_bytes().max_bytes(8).write_buffer(0);
+
+        let error = sink
+            .download_from_io_stream(Some(11), chunks(&[b"hello", b" world"]))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.kind(),
+            DlErrorKind::TooLarge {
+                limit: 8,
+                content_length: Some(11),
+            }
+        ));
+        assert!(sink.get_ref().get_ref().is_empty());
+
+        // without a known size, the download stops before the chunk that goes past the limit.
+        let error = sink
+            .download_from_io_stream(None, chunks(&[b"hello", b" world"]))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.kind(),
+            DlErrorKind::TooLarge { limit: 8, .. }
+        ));
+        assert_eq!(error.bytes_written(), 5);
+        assert_eq!(sink.get_ref().get_ref().as_ref(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn limits_each_download_on_its_own() {
+        let mut sink = DlSink::in_memory().max_bytes(5);
+
+        for chunk in [&b"hello"[..], b"world"] {
+            let copied = sink
+                .download_from_io_stream(Some(5), chunks(&[chunk]))
+                .await
+                .unwrap();
+            assert_eq!(copied, 5);
+        }
+
+        assert_eq!(sink.into_inner(), b"helloworld");
+    }
+}
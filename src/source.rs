@@ -0,0 +1,378 @@
+//! Transport-agnostic download sources.
+//!
+//! Anything that can produce a stream of bytes can be downloaded into a [`DlFile`] by
+//! implementing [`DlSource`] for it. Implementations are provided for [`reqwest::Response`]
+//! (with the default `reqwest` feature) and [`http::Response`] bodies (with the `http-body`
+//! feature, for hyper and other `http` based clients).
+//!
+//! [`DlFile`]: crate::DlFile
+use std::io;
+
+use bytes::Buf;
+use futures::Stream;
+use http::header::{self, HeaderMap};
+use http::StatusCode;
+
+/// Metadata about the data a [`DlSource`] produces.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SourceMetadata {
+    /// The HTTP status of the response, for HTTP sources.
+    pub status: Option<StatusCode>,
+    /// The number of bytes the source is expected to produce, if known.
+    pub content_length: Option<u64>,
+    /// The `ETag` validator of the data, if any.
+    pub etag: Option<String>,
+    /// The `Last-Modified` validator of the data, if any.
+    pub last_modified: Option<String>,
+    /// Whether the source supports byte range requests.
+    pub accepts_ranges: bool,
+    /// The offset of the first byte produced, from the `Content-Range` header of a partial
+    /// response.
+    pub range_start: Option<u64>,
+    /// The offset of the last byte produced (inclusive), from the `Content-Range` header of a
+    /// partial response.
+    pub range_end: Option<u64>,
+    /// The length of the whole file, from the `Content-Range` header of a partial response,
+    /// unless the server doesn't know it (`*`).
+    pub complete_length: Option<u64>,
+    /// The raw `Content-Disposition` header, which can suggest a file name.
+    pub content_disposition: Option<String>,
+    /// The (final, after any redirects) URL the data came from, if known.
+    pub url: Option<String>,
+}
+
+impl SourceMetadata {
+    /// Metadata with only a known (or unknown) length.
+    #[inline]
+    pub fn with_content_length(content_length: Option<u64>) -> Self {
+        Self {
+            content_length,
+            ..Default::default()
+        }
+    }
+
+    /// Pulls the validators and range info out of HTTP response headers. The
+    /// `content_length` is passed separately, since clients track it differently.
+    pub fn from_headers(headers: &HeaderMap, content_length: Option<u64>) -> Self {
+        let header_str = |name: header::HeaderName| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+
+        let content_range = header_str(header::CONTENT_RANGE)
+            .as_deref()
+            .and_then(parse_content_range);
+
+        Self {
+            content_length,
+            etag: header_str(header::ETAG),
+            last_modified: header_str(header::LAST_MODIFIED),
+            accepts_ranges: headers
+                .get_all(header::ACCEPT_RANGES)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .any(|unit| unit.trim().eq_ignore_ascii_case("bytes")),
+            range_start: content_range.map(|(start, _, _)| start),
+            range_end: content_range.map(|(_, end, _)| end),
+            complete_length: content_range.and_then(|(_, _, length)| length),
+            content_disposition: header_str(header::CONTENT_DISPOSITION),
+            status: None,
+            url: None,
+        }
+    }
+
+    /// Like [`from_headers`], with the status of the response.
+    ///
+    /// [`from_headers`]: Self::from_headers
+    #[inline]
+    pub fn from_response_parts(
+        status: StatusCode,
+        headers: &HeaderMap,
+        content_length: Option<u64>,
+    ) -> Self {
+        Self {
+            status: Some(status),
+            ..Self::from_headers(headers, content_length)
+        }
+    }
+
+    /// Sets the URL the data came from, for sources that don't know it themselves (i.e.
+    /// [`http::Response`], which only holds the response).
+    #[inline]
+    pub fn with_url(mut self, url: impl Into<String>) -> Self {
+        self.url = Some(url.into());
+        self
+    }
+}
+
+/// Parses the (inclusive) range and complete length out of a
+/// `Content-Range: bytes <start>-<end>/<length>` header, where the length can be `*`.
+fn parse_content_range(content_range: &str) -> Option<(u64, u64, Option<u64>)> {
+    let (unit, range) = content_range.trim().split_once(' ')?;
+
+    if !unit.eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+
+    let (range, length) = range.trim().split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let (start, end): (u64, u64) = (start.parse().ok()?, end.parse().ok()?);
+
+    let length = match length {
+        "*" => None,
+        length => Some(length.parse().ok()?),
+    };
+
+    (start <= end).then_some((start, end, length))
+}
+
+/// A source of bytes that can be downloaded into a [`DlFile`].
+///
+/// [`DlFile`]: crate::DlFile
+pub trait DlSource {
+    type Chunk: Buf;
+    type Stream: Stream<Item = io::Result<Self::Chunk>>;
+
+    /// Metadata about the data that [`into_stream`] produces.
+    ///
+    /// [`into_stream`]: Self::into_stream
+    fn metadata(&self) -> SourceMetadata;
+
+    fn into_stream(self) -> Self::Stream;
+}
+
+/// A [`DlSource`] built from any stream of bytes + some [`SourceMetadata`].
+#[derive(Debug, Clone)]
+pub struct StreamSource<S> {
+    metadata: SourceMetadata,
+    stream: S,
+}
+
+impl<S, B> StreamSource<S>
+where
+    S: Stream<Item = io::Result<B>>,
+    B: Buf,
+{
+    #[inline]
+    pub fn new(metadata: SourceMetadata, stream: S) -> Self {
+        Self { metadata, stream }
+    }
+}
+
+impl<S, B> DlSource for StreamSource<S>
+where
+    S: Stream<Item = io::Result<B>>,
+    B: Buf,
+{
+    type Chunk = B;
+    type Stream = S;
+
+    #[inline]
+    fn metadata(&self) -> SourceMetadata {
+        self.metadata.clone()
+    }
+
+    #[inline]
+    fn into_stream(self) -> Self::Stream {
+        self.stream
+    }
+}
+
+#[cfg(feature = "reqwest")]
+mod reqwest_source {
+    use std::io;
+    use std::pin::Pin;
+
+    use futures::stream::MapErr;
+    use futures::{Stream, TryStreamExt};
+
+    use super::{DlSource, SourceMetadata};
+
+    fn reqwest_error_to_io_error(error: reqwest::Error) -> io::Error {
+        io::Error::new(crate::error::reqwest_error_kind(&error), error)
+    }
+
+    impl DlSource for reqwest::Response {
+        type Chunk = bytes::Bytes;
+        type Stream = MapErr<
+            Pin<Box<dyn Stream<Item = reqwest::Result<bytes::Bytes>> + Send>>,
+            fn(reqwest::Error) -> io::Error,
+        >;
+
+        #[inline]
+        fn metadata(&self) -> SourceMetadata {
+            SourceMetadata::from_response_parts(
+                self.status(),
+                self.headers(),
+                self.content_length(),
+            )
+            .with_url(self.url().as_str())
+        }
+
+        #[inline]
+        fn into_stream(self) -> Self::Stream {
+            let stream: Pin<Box<dyn Stream<Item = _> + Send>> = Box::pin(self.bytes_stream());
+
+            stream.map_err(reqwest_error_to_io_error as fn(_) -> _)
+        }
+    }
+}
+
+#[cfg(feature = "http-body")]
+mod http_body_source {
+    use std::error::Error;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{ready, Context, Poll};
+
+    use futures::Stream;
+    use http_body::Body;
+
+    use super::{DlSource, SourceMetadata};
+
+    pin_project_lite::pin_project! {
+        /// Adapts an [`http_body::Body`] into a stream of its data frames. Trailers are
+        /// skipped.
+        pub struct BodyStream<B> {
+            #[pin]
+            body: B,
+        }
+    }
+
+    impl<B> Stream for BodyStream<B>
+    where
+        B: Body,
+        B::Error: Into<Box<dyn Error + Send + Sync>>,
+    {
+        type Item = io::Result<B::Data>;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            let mut this = self.project();
+
+            loop {
+                let frame = match ready!(this.body.as_mut().poll_frame(cx)) {
+                    Some(Ok(frame)) => frame,
+                    Some(Err(error)) => return Poll::Ready(Some(Err(io::Error::other(error)))),
+                    None => return Poll::Ready(None),
+                };
+
+                if let Ok(data) = frame.into_data() {
+                    return Poll::Ready(Some(Ok(data)));
+                }
+            }
+        }
+    }
+
+    impl<B> DlSource for http::Response<B>
+    where
+        B: Body,
+        B::Error: Into<Box<dyn Error + Send + Sync>>,
+    {
+        type Chunk = B::Data;
+        type Stream = BodyStream<B>;
+
+        fn metadata(&self) -> SourceMetadata {
+            let content_length = self
+                .headers()
+                .get(http::header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+                .or_else(|| self.body().size_hint().exact());
+
+            SourceMetadata::from_response_parts(self.status(), self.headers(), content_length)
+        }
+
+        #[inline]
+        fn into_stream(self) -> Self::Stream {
+            BodyStream {
+                body: self.into_body(),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "http-body")]
+pub use http_body_source::BodyStream;
+
+#[cfg(test)]
+mod tests {
+    use http::{header, HeaderMap, HeaderValue, StatusCode};
+
+    use super::{parse_content_range, SourceMetadata};
+
+    #[test]
+    fn parses_validators_and_ranges_from_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ETAG, HeaderValue::from_static("\"abc\""));
+        headers.insert(
+            header::LAST_MODIFIED,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        headers.append(header::ACCEPT_RANGES, HeaderValue::from_static("none"));
+        headers.append(
+            header::ACCEPT_RANGES,
+            HeaderValue::from_static("foo, Bytes"),
+        );
+        headers.insert(
+            header::CONTENT_RANGE,
+            HeaderValue::from_static("bytes 100-199/1000"),
+        );
+        headers.insert(
+            header::CONTENT_DISPOSITION,
+            HeaderValue::from_static("attachment; filename=\"a.txt\""),
+        );
+
+        let metadata =
+            SourceMetadata::from_response_parts(StatusCode::PARTIAL_CONTENT, &headers, Some(100))
+                .with_url("https://example.com/a.txt");
+
+        assert_eq!(metadata.status, Some(StatusCode::PARTIAL_CONTENT));
+        assert_eq!(metadata.content_length, Some(100));
+        assert_eq!(metadata.etag.as_deref(), Some("\"abc\""));
+        assert_eq!(
+            metadata.last_modified.as_deref(),
+            Some("Wed, 21 Oct 2015 07:28:00 GMT")
+        );
+        assert!(metadata.accepts_ranges);
+        assert_eq!(metadata.range_start, Some(100));
+        assert_eq!(metadata.range_end, Some(199));
+        assert_eq!(metadata.complete_length, Some(1000));
+        assert_eq!(
+            metadata.content_disposition.as_deref(),
+            Some("attachment; filename=\"a.txt\"")
+        );
+        assert_eq!(metadata.url.as_deref(), Some("https://example.com/a.txt"));
+    }
+
+    #[test]
+    fn missing_headers_leave_everything_unset() {
+        let metadata = SourceMetadata::from_headers(&HeaderMap::new(), None);
+
+        assert_eq!(metadata, SourceMetadata::default());
+        assert!(!metadata.accepts_ranges);
+    }
+
+    #[test]
+    fn parses_content_ranges() {
+        assert_eq!(
+            parse_content_range("bytes 0-99/100"),
+            Some((0, 99, Some(100)))
+        );
+        assert_eq!(parse_content_range(" BYTES 5-5/*"), Some((5, 5, None)));
+
+        for invalid in [
+            "bytes */100",
+            "bytes 10-5/100",
+            "bytes 0-99",
+            "bytes -5/100",
+            "items 0-99/100",
+            "bytes 0-99/abc",
+        ] {
+            assert_eq!(parse_content_range(invalid), None, "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Downloads that run in the background, on their own tokio task.
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll};
+
+use futures::future::{AbortHandle, Abortable};
+use tokio::task::{JoinError, JoinHandle};
+
+use crate::progress::{DlProgress, ProgressHandle, ProgressHandleShared};
+use crate::{DlError, DlErrorKind, DlFile, DlSource};
+
+/// The output of a spawned download: the file (so it can be inspected, reset, retried or
+/// dropped), and the result of the download.
+pub type SpawnedDownload<P> = (DlFile<P>, Result<u64, DlError>);
+
+/// A handle to a download started with [`DlFile::spawn_download`].
+///
+/// Awaiting the handle waits for the download to finish, like a [`JoinHandle`], and returns
+/// the file along with the result. Dropping the handle detaches the download, which keeps
+/// running in the background (and drops the file when it's done).
+#[derive(Debug)]
+pub struct DownloadHandle<P: AsRef<Path> = PathBuf> {
+    join: JoinHandle<SpawnedDownload<P>>,
+    abort: AbortHandle,
+    progress: Arc<ProgressHandleShared>,
+}
+
+impl<P: AsRef<Path>> DownloadHandle<P> {
+    /// Live progress of the download, updated as it runs, regardless of whether the file has
+    /// its own progress reporter (which still gets called as well).
+    #[inline]
+    pub fn progress(&self) -> &Arc<ProgressHandleShared> {
+        &self.progress
+    }
+
+    /// Stops the download at the next opportunity. The handle still resolves with the file,
+    /// and a [`DlErrorKind::Aborted`] error.
+    #[inline]
+    pub fn abort(&self) {
+        self.abort.abort();
+    }
+
+    /// Whether the download task has finished (successfully or not).
+    #[inline]
+    pub fn is_finished(&self) -> bool {
+        self.join.is_finished()
+    }
+}
+
+impl<P: AsRef<Path>> Future for DownloadHandle<P> {
+    /// Fails only if the download task panicked, or the runtime shut down before it finished.
+    type Output = Result<SpawnedDownload<P>, JoinError>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.get_mut().join).poll(cx)
+    }
+}
+
+impl<P: AsRef<Path> + Send + 'static> DlFile<P> {
+    /// Downloads from `source` on a new tokio task (see [`download_from_source`]), returning a
+    /// handle to follow, abort, or await the download.
+    ///
+    /// Semaphore permits are only held while the download runs, so spawning many downloads
+    /// at once is fine.
+    ///
+    /// [`download_from_source`]: Self::download_from_source
+    pub fn spawn_download<S>(mut self, source: S) -> DownloadHandle<P>
+    where
+        S: DlSource + Send + 'static,
+        S::Stream: Send,
+        S::Chunk: Send,
+    {
+        let handle: Arc<ProgressHandle> = Arc::new(ProgressHandle::without_callback());
+        let progress = Arc::clone(handle.shared());
+
+        // the file's own reporter is shared with the tee for the duration of the download, so
+        // it can be put back afterwards.
+        let inner = Arc::new(Mutex::new(self.progress.take()));
+        self.progress = Some(Box::new(TeeProgress {
+            handle,
+            inner: Arc::clone(&inner),
+        }));
+
+        let (abort, registration) = AbortHandle::new_pair();
+        let task_progress = Arc::clone(&progress);
+
+        let join = tokio::spawn(async move {
+            let result = Abortable::new(self.download_from_source(source), registration).await;
+
+            self.progress = inner.lock().unwrap_or_else(PoisonError::into_inner).take();
+
+            let result = match result {
+                Ok(result) => result,
+                Err(_aborted) => {
+                    let written = task_progress.get_bytes_written();
+                    Err(self.download_error(DlErrorKind::Aborted, written).await)
+                }
+            };
+
+            (self, result)
+        });
+
+        DownloadHandle {
+            join,
+            abort,
+            progress,
+        }
+    }
+}
+
+/// Reports progress to both the [`DownloadHandle`], and the file's own reporter (if any).
+struct TeeProgress {
+    handle: Arc<ProgressHandle>,
+    inner: Arc<Mutex<Option<Box<dyn DlProgress>>>>,
+}
+
+impl TeeProgress {
+    #[inline]
+    fn inner(&self, f: impl FnOnce(&mut dyn DlProgress)) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(ref mut inner) = *inner {
+            f(&mut **inner);
+        }
+    }
+}
+
+impl DlProgress for TeeProgress {
+    #[inline]
+    fn start(&mut self, path: &Path, total_bytes: Option<u64>) {
+        self.handle.start(path, total_bytes);
+        self.inner(|inner| inner.start(path, total_bytes));
+    }
+
+    #[inline]
+    fn update(&mut self, path: &Path, bytes_written: u64) {
+        self.handle.update(path, bytes_written);
+        self.inner(|inner| inner.update(path, bytes_written));
+    }
+
+    #[inline]
+    fn finished(&mut self, path: &Path) {
+        self.handle.finished(path);
+        self.inner(|inner| inner.finished(path));
+    }
+
+    #[inline]
+    fn paused(&mut self, path: &Path) {
+        self.handle.paused(path);
+        self.inner(|inner| inner.paused(path));
+    }
+
+    #[inline]
+    fn resumed(&mut self, path: &Path) {
+        self.handle.resumed(path);
+        self.inner(|inner| inner.resumed(path));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use tokio::io::AsyncWriteExt;
+
+    use crate::progress::ProgressContainer;
+    use crate::source::StreamSource;
+    use crate::{Delete, DlErrorKind, DlFile, SourceMetadata};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("dl-file-spawn-{name}-{}", std::process::id()))
+    }
+
+    /// A reporter that keeps the last number of bytes written.
+    fn last_update(bytes: &Arc<AtomicU64>) -> impl crate::progress::DlProgress {
+        ProgressContainer::new(
+            Arc::clone(bytes),
+            |_: &mut Arc<AtomicU64>, _: &Path, _| {},
+            |bytes: &mut Arc<AtomicU64>, _: &Path, written| bytes.store(written, Ordering::Relaxed),
+            |_: &mut Arc<AtomicU64>, _: &Path| {},
+        )
+    }
+
+    #[tokio::test]
+    async fn reports_progress_to_the_handle_and_the_file() {
+        let path = temp_path("progress");
+        let bytes = Arc::new(AtomicU64::new(0));
+
+        let file = DlFile::builder(path.clone())
+            .with_progress(last_update(&bytes))
+            .delete(Delete::No)
+            .open_overwrite()
+            .await
+            .unwrap();
+
+        let chunks =
+            futures::stream::iter([b"hello", b"world"].map(|chunk| Ok(Bytes::from_static(chunk))));
+        let source = StreamSource::new(SourceMetadata::with_content_length(Some(10)), chunks);
+
+        let handle = file.spawn_download(source);
+        let progress = Arc::clone(handle.progress());
+        let (file, result) = handle.await.unwrap();
+
+        assert_eq!(result.unwrap(), 10);
+        assert_eq!(progress.get_total_bytes(), Some(10));
+        assert_eq!(progress.get_bytes_written(), 10);
+        assert!(progress.is_finished());
+        assert_eq!(bytes.load(Ordering::Relaxed), 10);
+        // the file's own reporter is put back
+        assert!(file.progress.is_some());
+
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn aborted_downloads_return_the_file() {
+        let path = temp_path("abort");
+        let file = DlFile::builder(path.clone())
+            .delete(Delete::No)
+            .write_buffer(0)
+            .open_overwrite()
+            .await
+            .unwrap();
+
+        // sends a chunk, then stalls forever
+        let chunks = futures::stream::iter([Ok(Bytes::from_static(b"hello"))])
+            .chain(futures::stream::pending());
+        let source = StreamSource::new(SourceMetadata::default(), chunks);
+
+        let handle = file.spawn_download(source);
+
+        while handle.progress().get_bytes_written() < 5 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        assert!(!handle.is_finished());
+        handle.abort();
+        let (mut file, result) = handle.await.unwrap();
+
+        let error = result.unwrap_err();
+        assert!(matches!(error.kind(), DlErrorKind::Aborted));
+        assert_eq!(error.bytes_written(), 5);
+
+        // waits for the write in flight when it was aborted
+        file.flush().await.unwrap();
+        drop(file);
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
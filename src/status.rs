@@ -0,0 +1,182 @@
+use std::io;
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use http::StatusCode;
+
+use crate::source::SourceMetadata;
+use crate::DlErrorKind;
+
+/// Which response statuses are accepted before anything is written to the file.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct StatusPolicy {
+    /// `None` accepts any `2xx` status.
+    pub(crate) accepted: Option<Vec<StatusCode>>,
+    /// The max number of bytes of the body of a rejected response to keep in the error.
+    pub(crate) error_body_snippet: usize,
+}
+
+impl StatusPolicy {
+    #[inline]
+    fn is_accepted(&self, status: StatusCode) -> bool {
+        match self.accepted {
+            Some(ref accepted) => accepted.contains(&status),
+            None => status.is_success(),
+        }
+    }
+
+    /// Checks the status of a source that's about to be written at `position` in the file.
+    ///
+    /// `206 Partial Content` is only accepted when the response starts at `position`, since
+    /// that means a range for the rest of the file was requested. Otherwise the body would be
+    /// written at the wrong offset. When the server knows the length of the whole file, the
+    /// range also needs to reach its end, or the file would be silently truncated.
+    ///
+    /// A rejected status is returned without a body snippet, which is read separately by
+    /// [`read_body_snippet`] since it needs the stream.
+    pub(crate) fn check(
+        &self,
+        metadata: &SourceMetadata,
+        position: u64,
+    ) -> Result<(), DlErrorKind> {
+        let Some(status) = metadata.status else {
+            return Ok(());
+        };
+
+        if !self.is_accepted(status) {
+            return Err(DlErrorKind::Status { status, body: None });
+        }
+
+        if status != StatusCode::PARTIAL_CONTENT {
+            return Ok(());
+        }
+
+        if metadata.range_start != Some(position) {
+            return Err(DlErrorKind::UnexpectedRange {
+                expected_start: position,
+                range_start: metadata.range_start,
+            });
+        }
+
+        if let (Some(range_end), Some(complete_length)) =
+            (metadata.range_end, metadata.complete_length)
+        {
+            if range_end + 1 != complete_length {
+                return Err(DlErrorKind::IncompleteRange {
+                    range_end,
+                    complete_length,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads up to `max` bytes of the body of a rejected response, for diagnostics. Any errors
+/// reading the body just end the snippet early.
+pub(crate) async fn read_body_snippet<S, B>(stream: S, max: usize) -> Option<Bytes>
+where
+    S: Stream<Item = io::Result<B>>,
+    B: Buf,
+{
+    if max == 0 {
+        return None;
+    }
+
+    futures::pin_mut!(stream);
+
+    let mut snippet = BytesMut::new();
+
+    while snippet.len() < max {
+        let Some(Ok(mut chunk)) = stream.next().await else {
+            break;
+        };
+
+        let take = chunk.remaining().min(max - snippet.len());
+        snippet.extend_from_slice(&chunk.copy_to_bytes(take));
+    }
+
+    Some(snippet.freeze())
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::{HeaderMap, HeaderValue, CONTENT_RANGE};
+    use http::StatusCode;
+
+    use super::StatusPolicy;
+    use crate::source::SourceMetadata;
+    use crate::DlErrorKind;
+
+    fn partial(content_range: &'static str) -> SourceMetadata {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_RANGE, HeaderValue::from_static(content_range));
+        SourceMetadata::from_response_parts(StatusCode::PARTIAL_CONTENT, &headers, None)
+    }
+
+    #[test]
+    fn accepts_a_partial_response_for_the_rest_of_the_file() {
+        let policy = StatusPolicy::default();
+
+        assert!(policy.check(&partial("bytes 0-1023/1024"), 0).is_ok());
+        assert!(policy.check(&partial("bytes 512-1023/1024"), 512).is_ok());
+        // the server doesn't know the length, so the range can't be checked
+        assert!(policy.check(&partial("bytes 512-1023/*"), 512).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_partial_response_that_stops_early() {
+        let result = StatusPolicy::default().check(&partial("bytes 0-1023/10485760"), 0);
+
+        assert!(matches!(
+            result,
+            Err(DlErrorKind::IncompleteRange {
+                range_end: 1023,
+                complete_length: 10485760,
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_a_partial_response_at_the_wrong_offset() {
+        let policy = StatusPolicy::default();
+
+        assert!(matches!(
+            policy.check(&partial("bytes 100-1023/1024"), 0),
+            Err(DlErrorKind::UnexpectedRange {
+                expected_start: 0,
+                range_start: Some(100),
+            })
+        ));
+        assert!(matches!(
+            policy.check(&partial("bytes 0-/1024"), 0),
+            Err(DlErrorKind::UnexpectedRange {
+                range_start: None,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_statuses_that_arent_accepted() {
+        let policy = StatusPolicy {
+            accepted: Some(vec![StatusCode::OK]),
+            ..Default::default()
+        };
+
+        let metadata = SourceMetadata {
+            status: Some(StatusCode::NO_CONTENT),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            policy.check(&metadata, 0),
+            Err(DlErrorKind::Status {
+                status: StatusCode::NO_CONTENT,
+                body: None,
+            })
+        ));
+        assert!(policy.check(&partial("bytes 0-1023/1024"), 0).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+use std::io;
+use std::path::Path;
+
+use futures::future::BoxFuture;
+use tokio::fs::File;
+
+/// Duplicates the OS handle behind a tokio [`File`], so blocking work can be done on it from
+/// a `'static` context (i.e. [`tokio::task::spawn_blocking`]) without borrowing the [`File`].
+pub(crate) fn clone_std(file: &File) -> io::Result<std::fs::File> {
+    #[cfg(unix)]
+    {
+        use std::os::fd::AsFd;
+        Ok(std::fs::File::from(file.as_fd().try_clone_to_owned()?))
+    }
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::io::AsHandle;
+        Ok(std::fs::File::from(file.as_handle().try_clone_to_owned()?))
+    }
+}
+
+/// Builds a future that calls `sync_all` on the file, then fsyncs the parent directory of
+/// `path` so the directory entry itself is durable.
+///
+/// The returned future doesn't borrow `file`, which lets poll-based types store it.
+pub(crate) fn sync_file_and_parent(
+    file: &File,
+    path: &Path,
+) -> io::Result<BoxFuture<'static, io::Result<()>>> {
+    let std_file = clone_std(file)?;
+    let path = path.to_path_buf();
+
+    Ok(Box::pin(async move {
+        tokio::task::spawn_blocking(move || sync_file_and_parent_blocking(&std_file, &path))
+            .await
+            .map_err(io::Error::other)?
+    }))
+}
+
+/// The blocking equivalent of [`sync_file_and_parent`], which syncs right away.
+pub(crate) fn sync_file_and_parent_blocking(file: &std::fs::File, path: &Path) -> io::Result<()> {
+    file.sync_all()?;
+
+    match path.parent() {
+        Some(parent) => sync_dir(parent),
+        None => Ok(()),
+    }
+}
+
+/// fsyncs every directory from `to` up to `from` (the deepest pre-existing ancestor), so
+/// that newly created directories survive a crash.
+pub(crate) fn sync_dir_chain_blocking(from: &Path, to: &Path) -> io::Result<()> {
+    to.ancestors()
+        .take_while(|dir| *dir != from)
+        .chain(std::iter::once(from))
+        .try_for_each(sync_dir)
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+
+    std::fs::File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened + synced like this on non-unix platforms, and NTFS journals
+/// the metadata anyways.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use bytes::Bytes;
+
+    use super::{sync_dir_chain_blocking, sync_file_and_parent};
+    use crate::{DlFile, Durability};
+
+    #[tokio::test]
+    async fn syncs_files_and_their_parent() {
+        let path = std::env::temp_dir().join(format!("dl-file-sync-{}", std::process::id()));
+        let file = tokio::fs::File::create(&path).await.unwrap();
+
+        sync_file_and_parent(&file, &path).unwrap().await.unwrap();
+        // a bare file name's parent is the current directory.
+        sync_file_and_parent(&file, Path::new("file"))
+            .unwrap()
+            .await
+            .unwrap();
+
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn syncs_every_dir_in_the_chain() {
+        let root = std::env::temp_dir().join(format!("dl-file-sync-chain-{}", std::process::id()));
+        let nested = root.join("a/b");
+        std::fs::create_dir_all(&nested).unwrap();
+
+        sync_dir_chain_blocking(&root, &nested).unwrap();
+
+        // every directory is opened, so a missing one fails.
+        let error = sync_dir_chain_blocking(&root, &nested.join("missing")).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn synced_downloads_create_their_parent_dirs() {
+        let root = std::env::temp_dir().join(format!("dl-file-sync-dl-{}", std::process::id()));
+        let path = root.join("a/b/file");
+
+        let mut file = DlFile::builder(&path)
+            .durability(Durability::Sync)
+            .open_overwrite()
+            .await
+            .unwrap();
+
+        let chunks = futures::stream::iter([Ok(Bytes::from_static(b"hello"))]);
+        let copied = file.download_from_io_stream(Some(5), chunks).await.unwrap();
+
+        let contents = std::fs::read(&path).unwrap();
+        drop(file);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(copied, 5);
+        assert_eq!(contents, b"hello");
+    }
+}
@@ -0,0 +1,435 @@
+//! Downloading one source into several files at once.
+use std::future::Future;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::sync::Arc;
+
+use bytes::{Buf, Bytes};
+use futures::channel::mpsc;
+use futures::future::{self, Either};
+use futures::stream::FuturesUnordered;
+use futures::{SinkExt, Stream, StreamExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::progress::DlProgress;
+use crate::{permit, status, DlError, DlErrorKind, DlFile, DlSource};
+
+/// The number of chunks buffered for each file, which lets faster files get a little ahead of
+/// slower ones.
+const CHANNEL_CAPACITY: usize = 16;
+
+/// What a [`Tee`] does when one of its files fails.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TeeFailure {
+    /// Stop downloading into every file, and return the first error.
+    #[default]
+    FailAll,
+    /// Keep downloading into the rest of the files. The download only fails if every file
+    /// fails.
+    KeepGoing,
+}
+
+/// The result of a successful [`Tee`] download.
+#[derive(Debug)]
+pub struct TeeDownload {
+    /// The number of bytes downloaded into each file.
+    pub bytes: u64,
+    /// The files that failed (by index, in the order they were given) with
+    /// [`TeeFailure::KeepGoing`]. Always empty with [`TeeFailure::FailAll`].
+    pub failed: Vec<(usize, DlError)>,
+}
+
+/// Downloads one source into several [`DlFile`]s at once (i.e. a local cache, and a shared
+/// volume), instead of downloading it twice or copying it afterwards.
+///
+/// Each file is written like any other download, with its own [`Delete`] policy, size limit,
+/// durability and progress reporter. The source is only read as fast as the slowest file is
+/// written.
+///
+/// Semaphore permits are acquired once for the whole download, before anything is read: one
+/// per semaphore (with the largest [`PermitWeight`] of the files sharing it), so files that
+/// share a semaphore don't wait on each other. Pausing a file with
+/// [`PauseHandle::pause_releasing_permit`] doesn't give them back.
+///
+/// [`Delete`]: crate::Delete
+/// [`PermitWeight`]: crate::PermitWeight
+/// [`PauseHandle::pause_releasing_permit`]: crate::PauseHandle::pause_releasing_permit
+pub struct Tee<'a, P: AsRef<Path> = PathBuf> {
+    files: Vec<&'a mut DlFile<P>>,
+    on_failure: TeeFailure,
+    progress: Option<Box<dyn DlProgress>>,
+}
+
+impl<'a, P: AsRef<Path>> Tee<'a, P> {
+    #[inline]
+    pub fn new(files: impl IntoIterator<Item = &'a mut DlFile<P>>) -> Self {
+        Self {
+            files: files.into_iter().collect(),
+            on_failure: TeeFailure::default(),
+            progress: None,
+        }
+    }
+
+    /// Sets what happens when one of the files fails. Defaults to [`TeeFailure::FailAll`].
+    #[inline]
+    pub fn on_failure(mut self, on_failure: TeeFailure) -> Self {
+        self.on_failure = on_failure;
+        self
+    }
+
+    /// Reports the progress of the whole download, as the number of bytes handed to every
+    /// file that's still running. It's reported with the path of the first file.
+    #[inline]
+    pub fn with_progress(mut self, progress: impl DlProgress + 'static) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Downloads everything from a [`DlSource`] into every file, checking the response
+    /// status against the status policy of each file first (see
+    /// [`DlFile::download_from_source`]).
+    ///
+    /// With no files, the source is dropped, and nothing is downloaded.
+    pub async fn download_from_source<S>(self, source: S) -> Result<TeeDownload, DlError>
+    where
+        S: DlSource,
+    {
+        let metadata = source.metadata();
+        let mut files = Vec::with_capacity(self.files.len());
+        let mut failed = Vec::new();
+
+        for (index, file) in self.files.into_iter().enumerate() {
+            let Err(mut kind) = file.check_status(&metadata).await else {
+                files.push((index, file));
+                continue;
+            };
+
+            match self.on_failure {
+                TeeFailure::FailAll => {
+                    if let DlErrorKind::Status { ref mut body, .. } = kind {
+                        let max = file.status_policy.error_body_snippet;
+                        *body = status::read_body_snippet(source.into_stream(), max).await;
+                    }
+
+                    return Err(file.download_error(kind, 0).await);
+                }
+                TeeFailure::KeepGoing => failed.push((index, file.download_error(kind, 0).await)),
+            }
+        }
+
+        let size = metadata.content_length;
+        let stream = source.into_stream();
+
+        download(files, failed, self.on_failure, self.progress, size, stream).await
+    }
+
+    /// Downloads everything from a stream into every file. See
+    /// [`DlFile::download_from_io_stream`].
+    ///
+    /// With no files, the stream is dropped, and nothing is downloaded.
+    pub async fn download_from_io_stream<S, B>(
+        self,
+        size: Option<u64>,
+        stream: S,
+    ) -> Result<TeeDownload, DlError>
+    where
+        S: Stream<Item = io::Result<B>>,
+        B: Buf,
+    {
+        let files = self.files.into_iter().enumerate().collect();
+
+        download(
+            files,
+            Vec::new(),
+            self.on_failure,
+            self.progress,
+            size,
+            stream,
+        )
+        .await
+    }
+}
+
+async fn download<P, S, B>(
+    files: Vec<(usize, &mut DlFile<P>)>,
+    mut failed: Vec<(usize, DlError)>,
+    on_failure: TeeFailure,
+    mut progress: Option<Box<dyn DlProgress>>,
+    size: Option<u64>,
+    stream: S,
+) -> Result<TeeDownload, DlError>
+where
+    P: AsRef<Path>,
+    S: Stream<Item = io::Result<B>>,
+    B: Buf,
+{
+    let Some((_, first)) = files.first() else {
+        return finish(None, failed);
+    };
+
+    let path = first.path.as_ref().to_path_buf();
+
+    let _permits = match acquire_permits(&files, size).await {
+        Ok(permits) => permits,
+        Err(error) => {
+            let (_, file) = files.into_iter().next().unwrap();
+            return Err(file.download_error(error, 0).await);
+        }
+    };
+
+    let (senders, downloads): (Vec<_>, FuturesUnordered<_>) = files
+        .into_iter()
+        .map(|(index, file)| {
+            let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+            let download = async move {
+                let mut file = WithoutSemaphore::new(file);
+                (index, file.download_from_io_stream(size, receiver).await)
+            };
+
+            (sender, download)
+        })
+        .collect();
+
+    let bytes = {
+        let pump = pin!(pump(stream, senders, progress.as_mut(), &path, size));
+        let downloads = pin!(collect(downloads, on_failure, &mut failed));
+
+        // the pump finishes first unless every file fails, or one fails with `FailAll`, in
+        // which case the rest of the source isn't needed.
+        match future::select(downloads, pump).await {
+            Either::Left((bytes, _pump)) => bytes?,
+            Either::Right(((), downloads)) => downloads.await?,
+        }
+    };
+
+    if let (Some(prog), Some(_)) = (progress.as_mut(), bytes) {
+        prog.finished(&path);
+    }
+
+    finish(bytes, failed)
+}
+
+/// Acquires the permits for every file, once per semaphore. Each file waiting for its own
+/// permits would deadlock when files share a semaphore without enough free permits, since a
+/// file that's waiting never reads its chunks, and the source can't get past a full channel.
+///
+/// Semaphores are acquired in a fixed order (by address), so tees of files that share several
+/// semaphores can't deadlock each other either.
+async fn acquire_permits<P: AsRef<Path>>(
+    files: &[(usize, &mut DlFile<P>)],
+    size: Option<u64>,
+) -> io::Result<Vec<OwnedSemaphorePermit>> {
+    let mut semaphores: Vec<(Arc<Semaphore>, u32)> = Vec::new();
+
+    for (_, file) in files {
+        let Some(ref semaphore) = file.semaphore else {
+            continue;
+        };
+
+        let permits = file.permit_weight.permits(size);
+
+        match semaphores
+            .iter_mut()
+            .find(|(s, _)| Arc::ptr_eq(s, semaphore))
+        {
+            Some((_, max)) => *max = (*max).max(permits),
+            None => semaphores.push((Arc::clone(semaphore), permits)),
+        }
+    }
+
+    semaphores.sort_by_key(|(semaphore, _)| Arc::as_ptr(semaphore));
+
+    let mut acquired = Vec::with_capacity(semaphores.len());
+
+    for (semaphore, permits) in semaphores {
+        acquired.push(permit::acquire(semaphore, permits).await?);
+    }
+
+    Ok(acquired)
+}
+
+/// A file downloaded by a [`Tee`], with its semaphore taken away while the tee holds the
+/// permits. It's put back on drop, even if the download is cancelled.
+struct WithoutSemaphore<'a, P: AsRef<Path>> {
+    file: &'a mut DlFile<P>,
+    semaphore: Option<Arc<Semaphore>>,
+}
+
+impl<'a, P: AsRef<Path>> WithoutSemaphore<'a, P> {
+    #[inline]
+    fn new(file: &'a mut DlFile<P>) -> Self {
+        let semaphore = file.semaphore.take();
+        Self { file, semaphore }
+    }
+}
+
+impl<P: AsRef<Path>> Deref for WithoutSemaphore<'_, P> {
+    type Target = DlFile<P>;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        self.file
+    }
+}
+
+impl<P: AsRef<Path>> DerefMut for WithoutSemaphore<'_, P> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.file
+    }
+}
+
+impl<P: AsRef<Path>> Drop for WithoutSemaphore<'_, P> {
+    fn drop(&mut self) {
+        self.file.semaphore = self.semaphore.take();
+    }
+}
+
+/// Waits for every download, returning the number of bytes downloaded into the files that
+/// succeeded (if any did).
+async fn collect<F>(
+    mut downloads: FuturesUnordered<F>,
+    on_failure: TeeFailure,
+    failed: &mut Vec<(usize, DlError)>,
+) -> Result<Option<u64>, DlError>
+where
+    F: Future<Output = (usize, Result<u64, DlError>)>,
+{
+    let mut bytes = None;
+
+    while let Some((index, result)) = downloads.next().await {
+        match (result, on_failure) {
+            (Ok(written), _) => bytes = Some(written),
+            // dropping the rest of the downloads stops them where they are.
+            (Err(error), TeeFailure::FailAll) => return Err(error),
+            (Err(error), TeeFailure::KeepGoing) => failed.push((index, error)),
+        }
+    }
+
+    Ok(bytes)
+}
+
+/// Reads the source, handing every chunk to every file.
+async fn pump<S, B>(
+    stream: S,
+    mut senders: Vec<mpsc::Sender<io::Result<Bytes>>>,
+    mut progress: Option<&mut Box<dyn DlProgress>>,
+    path: &Path,
+    size: Option<u64>,
+) where
+    S: Stream<Item = io::Result<B>>,
+    B: Buf,
+{
+    futures::pin_mut!(stream);
+
+    if let Some(ref mut prog) = progress {
+        prog.start(path, size);
+    }
+
+    let mut sent = 0;
+
+    while let Some(chunk) = stream.next().await {
+        let mut chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(error) => return send_error(senders, error).await,
+        };
+
+        let chunk = chunk.copy_to_bytes(chunk.remaining());
+        let mut running = Vec::with_capacity(senders.len());
+
+        // files that failed have dropped their receiver, so they're left out from here on.
+        for mut sender in senders {
+            if sender.send(Ok(chunk.clone())).await.is_ok() {
+                running.push(sender);
+            }
+        }
+
+        if running.is_empty() {
+            return;
+        }
+
+        senders = running;
+        sent += chunk.len() as u64;
+
+        if let Some(ref mut prog) = progress {
+            prog.update(path, sent);
+        }
+    }
+}
+
+/// Fails every file with a source error. [`io::Error`] can't be cloned, so the first file
+/// gets the original error (keeping i.e. a reqwest error intact), and the rest get a copy.
+async fn send_error(senders: Vec<mpsc::Sender<io::Result<Bytes>>>, error: io::Error) {
+    let copy = || io::Error::new(error.kind(), error.to_string());
+    let mut errors: Vec<io::Error> = (1..senders.len()).map(|_| copy()).collect();
+    errors.push(error);
+
+    for (mut sender, error) in senders.into_iter().zip(errors.into_iter().rev()) {
+        let _ = sender.send(Err(error)).await;
+    }
+}
+
+#[inline]
+fn finish(bytes: Option<u64>, mut failed: Vec<(usize, DlError)>) -> Result<TeeDownload, DlError> {
+    failed.sort_by_key(|(index, _)| *index);
+
+    match (bytes, failed.is_empty()) {
+        (None, false) => Err(failed.remove(0).1),
+        (bytes, _) => Ok(TeeDownload {
+            bytes: bytes.unwrap_or(0),
+            failed,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use tokio::sync::Semaphore;
+
+    use crate::{DlFile, Tee};
+
+    #[tokio::test]
+    async fn files_sharing_a_semaphore_with_one_permit() {
+        let semaphore = Arc::new(Semaphore::new(1));
+        let dir = std::env::temp_dir().join(format!("dl-file-tee-{}", std::process::id()));
+        let (a, b) = (dir.join("a"), dir.join("b"));
+
+        let mut first = DlFile::builder(&a)
+            .with_semaphore_ref(&semaphore)
+            .open_overwrite()
+            .await
+            .unwrap();
+
+        let mut second = DlFile::builder(&b)
+            .with_semaphore_ref(&semaphore)
+            .open_overwrite()
+            .await
+            .unwrap();
+
+        // many more chunks than fit in a file's channel.
+        let chunks = (0..64u8).map(|i| Ok::<_, std::io::Error>(Bytes::from(vec![i; 1024])));
+        let stream = futures::stream::iter(chunks.collect::<Vec<_>>());
+        let download = Tee::new([&mut first, &mut second]).download_from_io_stream(None, stream);
+
+        let result = tokio::time::timeout(Duration::from_secs(10), download)
+            .await
+            .expect("the tee deadlocked");
+
+        let contents = (std::fs::read(&a).unwrap(), std::fs::read(&b).unwrap());
+        let semaphores = (first.semaphore.is_some(), second.semaphore.is_some());
+        drop((first, second));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(result.unwrap().bytes, 64 * 1024);
+        assert_eq!(contents.0.len(), 64 * 1024);
+        assert_eq!(contents.0, contents.1);
+        assert_eq!(semaphores, (true, true));
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+}
@@ -0,0 +1,583 @@
+//! Writing downloads through io_uring (with the `io-uring` feature, on Linux).
+//!
+//! Data is copied from the stream into a few registered buffers, which are written with
+//! positional `WRITE_FIXED` operations while the next buffer is being filled. Completions are
+//! signalled through an eventfd registered with the tokio reactor, so this works on any tokio
+//! runtime, without going through the blocking thread pool for every write.
+use std::io;
+use std::mem::ManuallyDrop;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+use std::pin::{pin, Pin};
+use std::task::Poll;
+
+use bytes::Buf;
+use futures::future::{self, Either};
+use futures::{Stream, StreamExt};
+use io_uring::{opcode, types, IoUring};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use crate::cache::CacheDropper;
+use crate::ranges::RangeSet;
+use crate::{DlErrorKind, DlFile};
+
+/// The number of registered buffers, which is also the max number of writes in flight.
+const QUEUE_DEPTH: usize = 4;
+
+/// The smallest registered buffer, since a buffer per chunk would defeat the point.
+const MIN_BUFFER: usize = 64 * 1024;
+
+/// A write that's in flight, from a registered buffer.
+#[derive(Debug, Clone, Copy)]
+struct Write {
+    pos: u64,
+    start: usize,
+    end: usize,
+}
+
+impl Write {
+    /// A write of the first `filled` bytes of a buffer, which end at `end_pos` in the file.
+    #[inline]
+    fn filled(end_pos: u64, filled: usize) -> Self {
+        Self {
+            pos: end_pos - filled as u64,
+            start: 0,
+            end: filled,
+        }
+    }
+}
+
+struct UringFile {
+    // the ring, file descriptor and buffers are taken by the `Drop` impl, since writes that
+    // are still in flight need them to stay alive.
+    ring: ManuallyDrop<IoUring>,
+    eventfd: AsyncFd<OwnedFd>,
+    // a duplicate of the download's file descriptor, closed with the ring.
+    fd: ManuallyDrop<OwnedFd>,
+    buffers: ManuallyDrop<Vec<Vec<u8>>>,
+    writes: [Option<Write>; QUEUE_DEPTH],
+    free: Vec<usize>,
+    // where the download starts in the file, and the ranges of the file that have been written
+    // so far. Completions arrive in any order, so a later write can land before an earlier one
+    // (or after one that failed).
+    start: u64,
+    completed: RangeSet,
+}
+
+impl UringFile {
+    /// Fails if io_uring isn't supported (or allowed) on this system.
+    fn new(file: &tokio::fs::File, buffer_size: usize) -> io::Result<Self> {
+        let ring = IoUring::new(QUEUE_DEPTH as u32 * 2)?;
+        let mut buffers: Vec<Vec<u8>> = (0..QUEUE_DEPTH).map(|_| vec![0; buffer_size]).collect();
+
+        let iovecs: Vec<libc::iovec> = buffers
+            .iter_mut()
+            .map(|buffer| libc::iovec {
+                iov_base: buffer.as_mut_ptr().cast(),
+                iov_len: buffer.len(),
+            })
+            .collect();
+
+        // SAFETY: the buffers live (and are never resized) as long as the ring, and in-flight
+        // writes are waited on before the ring is dropped.
+        unsafe { ring.submitter().register_buffers(&iovecs)? };
+
+        // SAFETY: eventfd returns a new file descriptor (which is owned from here on), or -1.
+        let eventfd = match unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) } {
+            -1 => return Err(io::Error::last_os_error()),
+            fd => unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+
+        ring.submitter().register_eventfd(eventfd.as_raw_fd())?;
+
+        // SAFETY: the `OwnedFd` is owned by the `AsyncFd`, so it stays open (and the same
+        // descriptor) as long as the `AsyncFd` lives.
+        let eventfd = unsafe { AsyncFd::register(eventfd)? };
+
+        Ok(Self {
+            ring: ManuallyDrop::new(ring),
+            eventfd,
+            fd: ManuallyDrop::new(OwnedFd::from(crate::sync::clone_std(file)?)),
+            buffers: ManuallyDrop::new(buffers),
+            writes: [None; QUEUE_DEPTH],
+            free: (0..QUEUE_DEPTH).rev().collect(),
+            start: 0,
+            completed: RangeSet::default(),
+        })
+    }
+
+    /// The number of bytes written from the start of the download, up to the first byte that
+    /// hasn't been (i.e. after a failed write), which is where it can be resumed from.
+    #[inline]
+    fn written(&self) -> u64 {
+        self.completed.contiguous_end(self.start) - self.start
+    }
+
+    /// The number of writes submitted, but not completed. Buffers that are being filled don't
+    /// count.
+    #[inline]
+    fn in_flight(&self) -> usize {
+        self.writes.iter().filter(|write| write.is_some()).count()
+    }
+
+    fn submit(&mut self, index: usize, write: Write) -> io::Result<()> {
+        let buffer = &self.buffers[index][write.start..write.end];
+
+        let entry = opcode::WriteFixed::new(
+            types::Fd(self.fd.as_raw_fd()),
+            buffer.as_ptr(),
+            buffer.len() as u32,
+            index as u16,
+        )
+        .offset(write.pos)
+        .build()
+        .user_data(index as u64);
+
+        // SAFETY: the buffer is registered, and isn't touched until the write completes.
+        // There should always be room in the submission queue, since it's twice as long as the
+        // number of buffers.
+        if unsafe { self.ring.submission().push(&entry) }.is_err() {
+            self.free.push(index);
+            return Err(io::Error::other("io_uring submission queue is full"));
+        }
+
+        // if submitting fails, the write is still queued, and gets submitted when drained.
+        self.writes[index] = Some(write);
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    /// Waits for at least one write to complete, or for nothing to be in flight. Short writes
+    /// are resubmitted for the rest of the buffer.
+    async fn wait(&mut self) -> io::Result<()> {
+        loop {
+            if self.reap()? || self.in_flight() == 0 {
+                return Ok(());
+            }
+
+            let mut guard = self.eventfd.readable().await?;
+
+            // reset the eventfd counter, so it's only readable after the next completion
+            let result = guard.try_io(|eventfd| {
+                let mut count = [0_u8; 8];
+
+                // SAFETY: reading 8 bytes into an 8 byte buffer.
+                match unsafe { libc::read(eventfd.as_raw_fd(), count.as_mut_ptr().cast(), 8) } {
+                    -1 => Err(io::Error::last_os_error()),
+                    _ => Ok(()),
+                }
+            });
+
+            if let Ok(result) = result {
+                result?;
+            }
+        }
+    }
+
+    /// Waits for every write in flight, ignoring their results, so nothing lands in the file
+    /// after the download returns. If waiting fails, the rest are waited on when dropped.
+    async fn drain(&mut self) {
+        // writes that failed to submit are still queued.
+        if self.ring.submit().is_err() {
+            return;
+        }
+
+        while self.in_flight() > 0 {
+            let in_flight = self.in_flight();
+
+            if self.wait().await.is_err() && self.in_flight() == in_flight {
+                return;
+            }
+        }
+    }
+
+    /// Handles every completion that's ready, without waiting. Returns whether anything
+    /// completed.
+    fn reap(&mut self) -> io::Result<bool> {
+        let completions: Vec<(usize, i32)> = self
+            .ring
+            .completion()
+            .map(|entry| (entry.user_data() as usize, entry.result()))
+            .collect();
+
+        let completed = !completions.is_empty();
+        let mut first_error = None;
+
+        // every completion needs handling (even after an error), so the in-flight writes are
+        // tracked correctly.
+        for (index, result) in completions {
+            let Some(mut write) = self.writes[index].take() else {
+                continue;
+            };
+
+            let written = match result {
+                ..0 => Err(io::Error::from_raw_os_error(-result)),
+                0 => Err(io::ErrorKind::WriteZero.into()),
+                written => Ok(written as usize),
+            };
+
+            let written = match written {
+                Ok(written) => written,
+                Err(error) => {
+                    self.free.push(index);
+                    first_error.get_or_insert(error);
+                    continue;
+                }
+            };
+
+            self.completed.insert(write.pos, write.pos + written as u64);
+            write.start += written;
+            write.pos += written as u64;
+
+            if write.start == write.end {
+                self.free.push(index);
+            } else if let Err(error) = self.submit(index, write) {
+                first_error.get_or_insert(error);
+            }
+        }
+
+        match first_error {
+            Some(error) => Err(error),
+            None => Ok(completed),
+        }
+    }
+}
+
+impl Drop for UringFile {
+    fn drop(&mut self) {
+        let in_flight = self.in_flight();
+
+        // SAFETY: these are only taken here, and never used again.
+        let (ring, fd, buffers) = unsafe {
+            (
+                ManuallyDrop::take(&mut self.ring),
+                ManuallyDrop::take(&mut self.fd),
+                ManuallyDrop::take(&mut self.buffers),
+            )
+        };
+
+        if in_flight == 0 {
+            return;
+        }
+
+        // the kernel is still reading from the buffers, so they need to outlive the writes.
+        // This only happens when a download is cancelled, and waiting would block the runtime,
+        // so it's done on a blocking thread (or right here, outside of a runtime).
+        let wait = move || {
+            if wait_for_writes(&ring, in_flight).is_err() {
+                // leaked, since freeing buffers the kernel may still read from is worse.
+                std::mem::forget((ring, buffers));
+            }
+
+            drop(fd);
+        };
+
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => drop(handle.spawn_blocking(wait)),
+            Err(_) => wait(),
+        }
+    }
+}
+
+/// Blocks until `in_flight` writes complete, submitting any that are still queued.
+fn wait_for_writes(ring: &IoUring, mut in_flight: usize) -> io::Result<()> {
+    while in_flight > 0 {
+        match ring.submit_and_wait(1) {
+            Ok(_) => (),
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        }
+
+        // SAFETY: nothing else is reading the completion queue, since the ring is owned here.
+        in_flight = in_flight.saturating_sub(unsafe { ring.completion_shared() }.count());
+    }
+
+    Ok(())
+}
+
+/// The io_uring equivalent of [`driver::DownloadDriver`], returning the result and the number
+/// of bytes written (for errors).
+///
+/// Returns `None` without touching the file if io_uring isn't available, so the caller can
+/// fall back to the driver.
+///
+/// [`driver::DownloadDriver`]: crate::driver::DownloadDriver
+pub(crate) async fn download<P, S, B>(
+    file: &mut DlFile<P>,
+    stream: Pin<&mut S>,
+    size: Option<u64>,
+    offset: u64,
+) -> Option<(Result<u64, DlErrorKind>, u64)>
+where
+    P: AsRef<Path>,
+    S: Stream<Item = io::Result<B>>,
+    B: Buf,
+{
+    let buffer_size = file.write_buffer.max(MIN_BUFFER);
+    let mut uring = UringFile::new(&file.file, buffer_size).ok()?;
+
+    let result = download_with(file, &mut uring, stream, size, offset).await;
+
+    // wait for anything still in flight after an error
+    uring.drain().await;
+
+    let written = uring.written();
+    Some((result.map(|()| written), written))
+}
+
+async fn download_with<P, S, B>(
+    file: &mut DlFile<P>,
+    uring: &mut UringFile,
+    mut stream: Pin<&mut S>,
+    size: Option<u64>,
+    offset: u64,
+) -> Result<(), DlErrorKind>
+where
+    P: AsRef<Path>,
+    S: Stream<Item = io::Result<B>>,
+    B: Buf,
+{
+    // anything written through tokio needs to land before the positional writes.
+    file.file.flush().await?;
+    let start = file.file.stream_position().await?;
+    uring.start = start;
+    let mut cache = CacheDropper::at(&file.file, start, file.page_cache)?;
+
+    let mut permit = file.permit_slot(size);
+    permit.acquire().await?;
+
+    if let Some(ref mut prog) = file.progress {
+        prog.start(file.path.as_ref(), size.map(|size| offset + size));
+
+        if offset > 0 {
+            prog.update(file.path.as_ref(), offset);
+        }
+    }
+
+    let mut paused = false;
+    let mut queued = 0_u64;
+    // the buffer being filled, and how much of it is filled.
+    let mut current: Option<(usize, usize)> = None;
+    let mut stream_error = None;
+
+    loop {
+        file.wait_while_paused(&mut paused, &mut permit).await?;
+
+        let next = match futures::poll!(stream.next()) {
+            Poll::Ready(next) => next,
+            // the stream is waiting, so write what's buffered in the meantime
+            Poll::Pending => {
+                if let Some((index, filled)) = current.take() {
+                    uring.submit(index, Write::filled(start + queued, filled))?;
+                }
+
+                loop {
+                    if uring.in_flight() == 0 {
+                        break stream.next().await;
+                    }
+
+                    let completed = match future::select(stream.next(), pin!(uring.wait())).await {
+                        Either::Left((next, _)) => break next,
+                        Either::Right((completed, _)) => completed,
+                    };
+
+                    completed?;
+                    completed_writes(file, uring, &mut cache, offset).await?;
+                }
+            }
+        };
+
+        let mut chunk = match next {
+            Some(Ok(chunk)) => chunk,
+            // write what was received before the error first
+            Some(Err(error)) => {
+                stream_error = Some(error);
+                break;
+            }
+            None => break,
+        };
+
+        if let Some(limit) = file.max_bytes {
+            if offset + queued + chunk.remaining() as u64 > limit {
+                return Err(DlErrorKind::TooLarge {
+                    limit,
+                    content_length: None,
+                });
+            }
+        }
+
+        while chunk.has_remaining() {
+            let (index, filled) = match current {
+                Some(current) => current,
+                None => loop {
+                    if let Some(index) = uring.free.pop() {
+                        break (index, 0);
+                    }
+
+                    uring.wait().await?;
+                    completed_writes(file, uring, &mut cache, offset).await?;
+                },
+            };
+
+            let count = chunk.remaining().min(buffer_len(uring) - filled);
+            chunk.copy_to_slice(&mut uring.buffers[index][filled..filled + count]);
+            queued += count as u64;
+
+            let filled = filled + count;
+
+            current = match filled == buffer_len(uring) {
+                true => {
+                    uring.submit(index, Write::filled(start + queued, filled))?;
+                    None
+                }
+                false => Some((index, filled)),
+            };
+        }
+    }
+
+    if let Some((index, filled)) = current {
+        uring.submit(index, Write::filled(start + queued, filled))?;
+    }
+
+    while uring.in_flight() > 0 {
+        uring.wait().await?;
+        completed_writes(file, uring, &mut cache, offset).await?;
+    }
+
+    if let Some(ref mut cache) = cache {
+        cache.finish(uring.written()).await?;
+    }
+
+    if let Some(error) = stream_error {
+        return Err(error.into());
+    }
+
+    // the writes use explicit offsets, so the file cursor needs to be moved manually.
+    file.file
+        .seek(io::SeekFrom::Start(start + uring.written()))
+        .await?;
+    file.finish_download().await?;
+
+    Ok(())
+}
+
+#[inline]
+fn buffer_len(uring: &UringFile) -> usize {
+    uring.buffers[0].len()
+}
+
+/// Reports progress after writes complete, and lets the [`CacheDropper`] (if any) know that
+/// everything before the contiguous end of the download is on its way to disk.
+async fn completed_writes<P: AsRef<Path>>(
+    file: &mut DlFile<P>,
+    uring: &UringFile,
+    cache: &mut Option<CacheDropper>,
+    offset: u64,
+) -> io::Result<()> {
+    let written = uring.written();
+
+    if let Some(ref mut prog) = file.progress {
+        prog.update(file.path.as_ref(), offset + written);
+    }
+
+    match cache {
+        Some(cache) => cache.written(written).await,
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::AsRawFd;
+
+    use bytes::Bytes;
+    use io_uring::{opcode, types};
+
+    use super::{UringFile, Write, MIN_BUFFER};
+    use crate::DlFile;
+
+    /// io_uring can be disabled (i.e. by a seccomp filter), in which case there's nothing to
+    /// test.
+    fn supported() -> bool {
+        io_uring::IoUring::new(2).is_ok()
+    }
+
+    #[tokio::test]
+    async fn downloads_through_the_ring() {
+        if !supported() {
+            return;
+        }
+
+        let path = std::env::temp_dir().join(format!("dl-file-uring-{}", std::process::id()));
+        let data: Vec<u8> = (0..1_000_000_u32).map(|i| (i % 251) as u8).collect();
+        let chunks: Vec<_> = data
+            .chunks(1000)
+            .map(|chunk| Ok::<_, std::io::Error>(Bytes::copy_from_slice(chunk)))
+            .collect();
+
+        let mut file = DlFile::builder(path.clone())
+            .io_uring(true)
+            .open_overwrite()
+            .await
+            .unwrap();
+
+        let bytes = file
+            .download_from_io_stream(Some(data.len() as u64), futures::stream::iter(chunks))
+            .await
+            .unwrap();
+
+        let contents = std::fs::read(&path).unwrap();
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(bytes, data.len() as u64);
+        assert!(contents == data);
+    }
+
+    #[tokio::test]
+    async fn written_stops_at_a_failed_write() {
+        if !supported() {
+            return;
+        }
+
+        let path = std::env::temp_dir().join(format!("dl-file-uring-hole-{}", std::process::id()));
+        let file = tokio::fs::File::create(&path).await.unwrap();
+        let mut uring = UringFile::new(&file, MIN_BUFFER).unwrap();
+        let len = MIN_BUFFER as u64;
+
+        // the second buffer is written after the first, and lands
+        uring.free.clear();
+        uring.submit(1, Write::filled(2 * len, MIN_BUFFER)).unwrap();
+
+        // the first points outside of its registered buffer, so it fails with EFAULT
+        let entry = opcode::WriteFixed::new(
+            types::Fd(uring.fd.as_raw_fd()),
+            uring.buffers[1].as_ptr(),
+            MIN_BUFFER as u32,
+            0,
+        )
+        .offset(0)
+        .build()
+        .user_data(0);
+
+        uring.writes[0] = Some(Write::filled(len, MIN_BUFFER));
+        unsafe { uring.ring.submission().push(&entry).unwrap() };
+        uring.ring.submit().unwrap();
+
+        let mut failed = false;
+
+        while uring.in_flight() > 0 {
+            failed |= uring.wait().await.is_err();
+        }
+
+        let (written, completed) = (uring.written(), uring.completed.len());
+        drop((uring, file));
+        std::fs::remove_file(&path).unwrap();
+
+        // only the second buffer landed, so nothing is contiguous from the start
+        assert!(failed);
+        assert_eq!(completed, len);
+        assert_eq!(written, 0);
+    }
+}
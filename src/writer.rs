@@ -1,16 +1,52 @@
-use std::io;
+use std::io::{self, Seek};
 use std::ops::{Deref, DerefMut};
 use std::path::Path;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
 
-use tokio::io::AsyncWrite;
+use futures::future::{self, BoxFuture};
+use tokio::io::{AsyncSeek, AsyncWrite, AsyncWriteExt};
 
-use crate::DlFile;
+use crate::cache::CacheDropper;
+use crate::permit::PermitSlot;
+use crate::ranges::RangeSet;
+use crate::{Delete, DlError, DlErrorKind, DlFile, Durability, PartialFile};
 
+/// Writes a download into a [`DlFile`] through [`AsyncWrite`], for sources that don't fit
+/// the download methods on [`DlFile`].
+///
+/// The writer can seek (with [`AsyncSeek`]), and write at any offset without moving its
+/// cursor (with [`write_at`]), so ranges can be written out of order, i.e. by a client for a
+/// chunked protocol. Progress counts every byte of the file that's been written at least once,
+/// so rewriting a range doesn't push it past the estimated size.
+///
+/// [`write_at`]: Self::write_at
 pub struct DlFileWriter<P: AsRef<Path>> {
     dst: DlFile<P>,
-    written: u64,
+    written: RangeSet,
+    // the position of the cursor in the file.
+    pos: u64,
+    // a seek was started through `AsyncSeek`, and `pos` is updated once it completes.
+    seeking: bool,
+    // a handle for positional writes, only duplicated when one is needed.
+    positional: Option<Arc<std::fs::File>>,
+    est_size: Option<u64>,
+    created: Instant,
+    sync: Option<BoxFuture<'static, io::Result<()>>>,
+    cache: Option<CacheDropper>,
+    permit: PermitSlot,
+    paused: bool,
+}
+
+/// The outcome of a [`DlFileWriter::finish`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriterReport {
+    /// The number of bytes written into the file (counting any rewritten ranges once).
+    pub bytes: u64,
+    /// The time since the writer was created.
+    pub elapsed: Duration,
 }
 
 impl<P: AsRef<Path>> Deref for DlFileWriter<P> {
@@ -33,21 +69,223 @@ impl<P: AsRef<Path>> DlFileWriter<P> {
             prog.start(dst.path.as_ref(), est_size);
         }
 
-        Self { dst, written: 0 }
+        // the tokio file isn't busy right after being handed over, so the OS cursor is the
+        // real position.
+        let pos = crate::sync::clone_std(&dst.file)
+            .and_then(|mut file| file.stream_position())
+            .unwrap_or(0);
+
+        Self {
+            written: RangeSet::default(),
+            pos,
+            seeking: false,
+            positional: None,
+            est_size,
+            created: Instant::now(),
+            sync: None,
+            // the page cache is only advice, so failing to duplicate the file handle for it isn't
+            // worth failing over.
+            cache: CacheDropper::at(&dst.file, pos, dst.page_cache)
+                .ok()
+                .flatten(),
+            permit: dst.permit_slot(est_size),
+            paused: false,
+            dst,
+        }
+    }
+
+    /// Writes `buf` at `offset` in the file, without moving the cursor (like
+    /// [`FileExt::write_at`]). Returns the number of bytes written, which can be less than
+    /// `buf.len()`.
+    ///
+    /// Anything written through [`AsyncWrite`] is flushed first, so it can't land on top of
+    /// this.
+    ///
+    /// [`FileExt::write_at`]: std::os::unix::fs::FileExt::write_at
+    pub async fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.check_limit(offset, buf.len())?;
+        futures::future::poll_fn(|cx| self.poll_ready(cx)).await?;
+
+        self.dst.file.flush().await?;
+
+        let file = match self.positional {
+            Some(ref file) => Arc::clone(file),
+            None => {
+                let file = Arc::new(crate::sync::clone_std(&self.dst.file)?);
+                Arc::clone(self.positional.insert(file))
+            }
+        };
+
+        let buf = buf.to_vec();
+        let written = tokio::task::spawn_blocking(move || write_at(&file, &buf, offset))
+            .await
+            .map_err(io::Error::other)??;
+
+        self.handle_write(offset, written);
+        Ok(written)
     }
 
+    /// Writes all of `buf` at `offset` in the file, without moving the cursor. See
+    /// [`write_at`].
+    ///
+    /// [`write_at`]: Self::write_at
+    pub async fn write_all_at(&mut self, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.write_at(buf, offset).await {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    buf = &buf[written..];
+                    offset += written as u64;
+                }
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Waits while the download is paused, then for permits from the semaphore (if there is
+    /// one), which are held until the writer is shut down. Fails if the semaphore is closed.
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(ref pause) = self.dst.pause {
+            ready!(pause.poll_paused(
+                cx,
+                &mut self.paused,
+                &mut self.permit,
+                self.dst.progress.as_deref_mut(),
+                self.dst.path.as_ref(),
+            ));
+        }
+
+        ready!(self.permit.poll_acquire(cx))?;
+
+        match self.cache {
+            Some(ref mut cache) => {
+                let start = cache.start();
+                cache.poll_written(cx, self.written.contiguous_end(start) - start)
+            }
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    /// Checks that writing `len` bytes at `offset` keeps the file within
+    /// [`DlFileBuilder::max_bytes`], and that the estimated size isn't already over it.
+    ///
+    /// [`DlFileBuilder::max_bytes`]: crate::DlFileBuilder::max_bytes
+    fn check_limit(&mut self, offset: u64, len: usize) -> io::Result<()> {
+        let Some(limit) = self.dst.max_bytes else {
+            return Ok(());
+        };
+
+        let content_length = self.est_size.filter(|size| *size > limit);
+
+        if content_length.is_none() && offset + len as u64 <= limit {
+            return Ok(());
+        }
+
+        // same as the driver, a download that's too large is never useful.
+        if let Delete::IfEmptyOnDrop = self.dst.delete {
+            self.dst.delete = Delete::Yes;
+        }
+
+        let partial_file = match self.dst.delete {
+            Delete::No => PartialFile::Kept,
+            _ => PartialFile::Deleted,
+        };
+
+        let error = DlError::new(
+            DlErrorKind::TooLarge {
+                limit,
+                content_length,
+            },
+            self.dst.path.as_ref().to_path_buf(),
+            self.written.len(),
+            partial_file,
+        );
+
+        Err(error.into())
+    }
+
+    /// Records `count` bytes written at `offset`, only reporting progress for bytes that
+    /// weren't written before.
     #[inline]
-    fn handle_write(&mut self, count: usize) {
-        self.written += count as u64;
+    fn handle_write(&mut self, offset: u64, count: usize) {
+        let added = self.written.insert(offset, offset + count as u64);
 
-        if count > 0 {
+        if added > 0 {
             if let Some(ref mut prog) = self.dst.progress {
-                prog.update(self.dst.path.as_ref(), self.written);
+                prog.update(self.dst.path.as_ref(), self.written.len());
             }
         }
     }
 }
 
+impl<P: AsRef<Path> + Unpin> DlFileWriter<P> {
+    /// Flushes the file, drops it from the page cache and syncs it (depending on the file's
+    /// settings), then releases the permits. The download is only reported as finished if
+    /// `report_finished` is set.
+    fn poll_close(&mut self, cx: &mut Context<'_>, report_finished: bool) -> Poll<io::Result<()>> {
+        ready!(Pin::new(&mut *self.dst.file).poll_shutdown(cx))?;
+
+        if let Some(ref mut cache) = self.cache {
+            let start = cache.start();
+            ready!(cache.poll_finish(cx, self.written.end().saturating_sub(start)))?;
+        }
+
+        if self.dst.durability == Durability::Sync {
+            let sync = match self.sync {
+                Some(ref mut sync) => sync,
+                None => self.sync.insert(crate::sync::sync_file_and_parent(
+                    &self.dst.file,
+                    self.dst.path.as_ref(),
+                )?),
+            };
+
+            ready!(sync.as_mut().poll(cx))?;
+        }
+
+        if let (true, Some(prog)) = (report_finished, self.dst.progress.as_mut()) {
+            prog.finished(self.dst.path.as_ref());
+        }
+
+        self.permit.release();
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Shuts down the writer, then drops the file (according to its [`Delete`] policy),
+    /// reporting how much was written and how long it took.
+    ///
+    /// If an estimated size was given to [`DlFile::into_async_writer`], and the number of
+    /// bytes written doesn't match it, a [`DlErrorKind::SizeMismatch`] error is returned
+    /// instead (with the [`DlError`] as the inner error), and the download isn't reported as
+    /// finished. What was written is still shut down the same way otherwise (flushed, synced
+    /// and dropped from the page cache, depending on the file's settings).
+    pub async fn finish(mut self) -> io::Result<WriterReport> {
+        let bytes = self.written.len();
+
+        if let Some(expected) = self.est_size.filter(|expected| *expected != bytes) {
+            let kind = match future::poll_fn(|cx| self.poll_close(cx, false)).await {
+                Ok(()) => DlErrorKind::SizeMismatch {
+                    expected,
+                    written: bytes,
+                },
+                Err(error) => error.into(),
+            };
+
+            return Err(self.dst.download_error(kind, bytes).await.into());
+        }
+
+        self.shutdown().await?;
+
+        Ok(WriterReport {
+            bytes,
+            elapsed: self.created.elapsed(),
+        })
+    }
+}
+
 impl<P: AsRef<Path> + Unpin> AsyncWrite for DlFileWriter<P> {
     #[inline]
     fn poll_write(
@@ -56,8 +294,12 @@ impl<P: AsRef<Path> + Unpin> AsyncWrite for DlFileWriter<P> {
         buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
         let this = self.get_mut();
+        this.check_limit(this.pos, buf.len())?;
+        ready!(this.poll_ready(cx))?;
+
         let written = ready!(Pin::new(&mut *this.dst.file).poll_write(cx, buf))?;
-        this.handle_write(written);
+        this.handle_write(this.pos, written);
+        this.pos += written as u64;
         Poll::Ready(Ok(written))
     }
 
@@ -68,15 +310,7 @@ impl<P: AsRef<Path> + Unpin> AsyncWrite for DlFileWriter<P> {
 
     #[inline]
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        let this = self.get_mut();
-
-        ready!(Pin::new(&mut *this.dst.file).poll_shutdown(cx))?;
-
-        if let Some(ref mut prog) = this.dst.progress {
-            prog.finished(this.dst.path.as_ref());
-        }
-
-        Poll::Ready(Ok(()))
+        self.get_mut().poll_close(cx, true)
     }
 
     #[inline]
@@ -91,8 +325,162 @@ impl<P: AsRef<Path> + Unpin> AsyncWrite for DlFileWriter<P> {
         bufs: &[io::IoSlice<'_>],
     ) -> Poll<Result<usize, io::Error>> {
         let this = self.get_mut();
+        this.check_limit(this.pos, bufs.iter().map(|buf| buf.len()).sum())?;
+        ready!(this.poll_ready(cx))?;
+
         let written = ready!(Pin::new(&mut *this.dst.file).poll_write_vectored(cx, bufs))?;
-        this.handle_write(written);
+        this.handle_write(this.pos, written);
+        this.pos += written as u64;
         Poll::Ready(Ok(written))
     }
 }
+
+impl<P: AsRef<Path> + Unpin> AsyncSeek for DlFileWriter<P> {
+    #[inline]
+    fn start_seek(self: Pin<&mut Self>, position: io::SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+
+        Pin::new(&mut *this.dst.file).start_seek(position)?;
+        this.seeking = true;
+        Ok(())
+    }
+
+    #[inline]
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+
+        let result = ready!(Pin::new(&mut *this.dst.file).poll_complete(cx));
+
+        // without a seek in progress, tokio returns the position it last saw, which is stale
+        // after writes (and positional writes never move it), so only seeks update `pos`.
+        if let (true, Ok(pos)) = (std::mem::take(&mut this.seeking), &result) {
+            this.pos = *pos;
+        }
+
+        Poll::Ready(result)
+    }
+}
+
+#[cfg(unix)]
+#[inline]
+fn write_at(file: &std::fs::File, buf: &[u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::write_at(file, buf, offset)
+}
+
+/// `seek_write` moves the cursor on Windows, which is shared with the tokio file, so it's put
+/// back afterwards.
+#[cfg(windows)]
+fn write_at(mut file: &std::fs::File, buf: &[u8], offset: u64) -> io::Result<usize> {
+    let pos = file.stream_position()?;
+    let written = std::os::windows::fs::FileExt::seek_write(file, buf, offset)?;
+    file.seek(io::SeekFrom::Start(pos))?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::DlFileWriter;
+    use crate::progress::ProgressContainer;
+    use crate::{Delete, DlError, DlErrorKind, DlFile, Durability};
+
+    /// Opens a writer that reports whether the download finished, and keeps the file.
+    async fn writer(
+        name: &str,
+        est_size: u64,
+    ) -> (PathBuf, DlFileWriter<PathBuf>, Arc<AtomicBool>) {
+        let path = std::env::temp_dir().join(format!("dl-file-{name}-{}", std::process::id()));
+        let finished = Arc::new(AtomicBool::new(false));
+
+        let progress = ProgressContainer::new(
+            Arc::clone(&finished),
+            |_: &mut Arc<AtomicBool>, _: &Path, _| {},
+            |_: &mut Arc<AtomicBool>, _: &Path, _| {},
+            |finished: &mut Arc<AtomicBool>, _: &Path| finished.store(true, Ordering::Relaxed),
+        );
+
+        let file = DlFile::builder(path.clone())
+            .with_progress(progress)
+            .durability(Durability::Sync)
+            .delete(Delete::No)
+            .open_overwrite()
+            .await
+            .unwrap();
+
+        (path, file.into_async_writer(Some(est_size)), finished)
+    }
+
+    #[tokio::test]
+    async fn finish_reports_what_was_written() {
+        let (path, mut writer, finished) = writer("writer-finish", 10).await;
+
+        writer.write_all_at(b"world", 5).await.unwrap();
+        writer.write_all(b"hello").await.unwrap();
+        let report = writer.finish().await.unwrap();
+
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(report.bytes, 10);
+        assert_eq!(contents, b"helloworld");
+        assert!(finished.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn finish_with_a_size_mismatch() {
+        let (path, mut writer, finished) = writer("writer-mismatch", 10).await;
+
+        writer.write_all(b"hello").await.unwrap();
+        let error = writer.finish().await.unwrap_err();
+
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let error = error.into_inner().unwrap().downcast::<DlError>().unwrap();
+        assert!(matches!(
+            error.kind(),
+            DlErrorKind::SizeMismatch {
+                expected: 10,
+                written: 5
+            }
+        ));
+        assert_eq!(error.bytes_written(), 5);
+        assert_eq!(contents, b"hello");
+        assert!(!finished.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn writes_past_the_limit_fail() {
+        let path =
+            std::env::temp_dir().join(format!("dl-file-writer-limit-{}", std::process::id()));
+        let file = DlFile::builder(path.clone())
+            .max_bytes(8)
+            .open_overwrite()
+            .await
+            .unwrap();
+
+        let mut writer = file.into_async_writer(None);
+        writer.write_all(b"hello").await.unwrap();
+
+        let error = writer.write_all(b" world").await.unwrap_err();
+        let error = error.downcast::<DlError>().unwrap();
+
+        assert!(matches!(
+            error.kind(),
+            DlErrorKind::TooLarge {
+                limit: 8,
+                content_length: None,
+            }
+        ));
+        assert_eq!(error.bytes_written(), 5);
+
+        // too large to keep, even though it isn't empty
+        drop(writer);
+        assert!(!path.exists());
+    }
+}